@@ -0,0 +1,213 @@
+/* Camera animation: the camera goes through keyframes, and is interpolated
+ * in between to render a sequence of frames. */
+
+use crate::camera::{Camera, Fov};
+use crate::maths::Vec3;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+fn default_fov() -> f64 {
+    90.
+}
+
+/* Where the camera is at `time`, in seconds. `fov` is horizontal, in
+ * degrees */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub time: f64,
+    pub position: Vec3,
+    pub direction: Vec3,
+    #[serde(default = "default_fov")]
+    pub fov: f64,
+}
+
+impl Keyframe {
+    /* Values that get interpolated */
+    fn values(&self) -> [f64; 7] {
+        let d = self.direction.normalize();
+        [
+            self.position.x,
+            self.position.y,
+            self.position.z,
+            d.x,
+            d.y,
+            d.z,
+            self.fov,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    /* goes through every keyframe with a continuous velocity */
+    CatmullRom,
+}
+
+impl Interpolation {
+    pub fn parse(val: &str) -> Result<Interpolation, String> {
+        match val {
+            "linear" => Ok(Interpolation::Linear),
+            "catmull-rom" => Ok(Interpolation::CatmullRom),
+            _ => Err("invalid interpolation".to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub keyframes: Vec<Keyframe>,
+    pub interpolation: Interpolation,
+}
+
+impl Animation {
+    pub fn new(
+        mut keyframes: Vec<Keyframe>,
+        interpolation: Interpolation,
+    ) -> Result<Animation, String> {
+        if keyframes.is_empty() {
+            return Err("no keyframes".to_owned());
+        }
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        for w in keyframes.windows(2) {
+            if w[0].time == w[1].time {
+                return Err(format!("two keyframes at {}s", w[0].time));
+            }
+        }
+        if keyframes.iter().any(|k| k.direction.length_sq() == 0.) {
+            return Err("keyframe direction can not be null".to_owned());
+        }
+        Ok(Animation {
+            keyframes,
+            interpolation,
+        })
+    }
+
+    /* Keyframes are read from a JSON array */
+    pub fn load(path: &Path, interpolation: Interpolation) -> Result<Animation, String> {
+        let f = File::open(path).map_err(|e| format!("couldn't open {}: {}", path.display(), e))?;
+        let keyframes = serde_json::from_reader(BufReader::new(f))
+            .map_err(|e| format!("couldn't parse {}: {}", path.display(), e))?;
+        Animation::new(keyframes, interpolation)
+    }
+
+    pub fn start(&self) -> f64 {
+        self.keyframes[0].time
+    }
+    pub fn end(&self) -> f64 {
+        self.keyframes[self.keyframes.len() - 1].time
+    }
+    /* Number of frames from the first keyframe to the last one */
+    pub fn nb_frames(&self, fps: f64) -> u32 {
+        ((self.end() - self.start()) * fps + 1e-9).floor() as u32 + 1
+    }
+
+    /* Slope of the values at keyframe `i`, averaged over its neighbours */
+    fn tangent(&self, i: usize) -> [f64; 7] {
+        let k = &self.keyframes;
+        let (a, b) = (i.saturating_sub(1), (i + 1).min(k.len() - 1));
+        let (va, vb) = (k[a].values(), k[b].values());
+        let dt = k[b].time - k[a].time;
+        let mut m = [0.; 7];
+        for c in 0..7 {
+            m[c] = (vb[c] - va[c]) / dt;
+        }
+        m
+    }
+
+    fn values_at(&self, time: f64) -> [f64; 7] {
+        let k = &self.keyframes;
+        if k.len() == 1 || time <= self.start() {
+            return k[0].values();
+        }
+        if time >= self.end() {
+            return k[k.len() - 1].values();
+        }
+        let i = k.iter().rposition(|f| f.time <= time).unwrap();
+        let (p0, p1) = (k[i].values(), k[i + 1].values());
+        let dt = k[i + 1].time - k[i].time;
+        let s = (time - k[i].time) / dt;
+        let mut v = [0.; 7];
+        match self.interpolation {
+            Interpolation::Linear => {
+                for c in 0..7 {
+                    v[c] = p0[c] + s * (p1[c] - p0[c]);
+                }
+            }
+            Interpolation::CatmullRom => {
+                /* cubic Hermite spline, keyframes being unevenly spaced */
+                let (m0, m1) = (self.tangent(i), self.tangent(i + 1));
+                let (s2, s3) = (s * s, s * s * s);
+                let h00 = 2. * s3 - 3. * s2 + 1.;
+                let h10 = s3 - 2. * s2 + s;
+                let h01 = -2. * s3 + 3. * s2;
+                let h11 = s3 - s2;
+                for c in 0..7 {
+                    v[c] = h00 * p0[c] + h10 * dt * m0[c] + h01 * p1[c] + h11 * dt * m1[c];
+                }
+            }
+        }
+        v
+    }
+
+    /* `camera` moved to where it is at `time`, keeping its other settings */
+    pub fn camera_at(&self, camera: &Camera, time: f64) -> Camera {
+        let v = self.values_at(time);
+        let mut c = camera.clone();
+        c.position = Vec3::new(v[0], v[1], v[2]);
+        let mut direction = Vec3::new(v[3], v[4], v[5]);
+        if direction.length_sq() < 1e-12 {
+            direction = self.keyframes[0].direction.clone();
+        }
+        c.look_at = c.position.addv(&direction);
+        c.fov = Fov::Horizontal(v[6]);
+        c
+    }
+}
+
+/* `pngpath` numbered for `frame`, as in frame_0001.png */
+pub fn frame_path(pngpath: &str, frame: u32) -> String {
+    let path = Path::new(pngpath);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{:04}.{}", stem, frame, ext.to_string_lossy()),
+        None => format!("{}_{:04}", stem, frame),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(time: f64, x: f64, fov: f64) -> Keyframe {
+        Keyframe {
+            time,
+            position: Vec3::new(x, 0., 0.),
+            direction: Vec3::new(0., 0., 2.),
+            fov,
+        }
+    }
+
+    #[test]
+    fn cameras_go_through_keyframes() {
+        let keys = vec![key(2., 10., 60.), key(0., 0., 90.), key(1., 4., 90.)];
+        let base = Camera::new(Vec3::origin(), &Vec3::new(1., 0., 0.));
+        for interpolation in &[Interpolation::Linear, Interpolation::CatmullRom] {
+            let anim = Animation::new(keys.clone(), *interpolation).unwrap();
+            assert_eq!(anim.nb_frames(24.), 49);
+            for k in &keys {
+                let c = anim.camera_at(&base, k.time);
+                assert!(c.position.length_sq_to(&k.position) < 1e-18);
+                assert!(c.direction().to(&Vec3::new(0., 0., 1.)).length_sq() < 1e-18);
+                assert_eq!(c.fov, Fov::Horizontal(k.fov));
+            }
+        }
+        let anim = Animation::new(keys, Interpolation::Linear).unwrap();
+        let c = anim.camera_at(&base, 1.5);
+        assert!((c.position.x - 7.).abs() < 1e-12);
+        assert_eq!(c.fov, Fov::Horizontal(75.));
+        assert_eq!(frame_path("out/frame.png", 1), "out/frame_0001.png");
+    }
+}
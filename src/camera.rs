@@ -0,0 +1,364 @@
+use crate::maths::{Aabb, Vec3};
+use std::f64::consts::PI;
+
+/* Field of view, in degrees, across the width or the height of the image.
+ * The other one follows from the aspect ratio */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Fov {
+    Horizontal(f64),
+    Vertical(f64),
+}
+
+impl Fov {
+    /* Horizontal and vertical fields of view, in radians */
+    pub fn angles(self, aspect_ratio: f64) -> (f64, f64) {
+        match self {
+            Fov::Horizontal(h) => (h.to_radians(), h.to_radians() / aspect_ratio),
+            Fov::Vertical(v) => (v.to_radians() * aspect_ratio, v.to_radians()),
+        }
+    }
+    /* Half width and half height of the image plane at unit distance */
+    pub fn plane(self, aspect_ratio: f64) -> (f64, f64) {
+        match self {
+            Fov::Horizontal(h) => {
+                let t = (h.to_radians() / 2.).tan();
+                (t, t / aspect_ratio)
+            }
+            Fov::Vertical(v) => {
+                let t = (v.to_radians() / 2.).tan();
+                (t * aspect_ratio, t)
+            }
+        }
+    }
+}
+
+/* How directions from the camera map to the image.
+ * `Legacy` turns the viewing direction horizontally and vertically by angles
+ * proportional to the position in the image, which bends straight lines
+ * near the edges. Scenes generated by `extract` rely on it.
+ * `Rectilinear` is a pinhole with the image plane at unit distance.
+ * `Orthographic` casts parallel rays from a `ortho_width` wide rectangle.
+ * `Fisheye` is equidistant: the angle to the viewing direction is
+ * proportional to the distance to the center of the image.
+ * `Equirectangular` covers every direction, longitude along the width and
+ * latitude along the height, whatever the field of view */
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Projection {
+    #[default]
+    Legacy,
+    Rectilinear,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+}
+
+impl Projection {
+    pub fn parse(val: &str) -> Result<Projection, String> {
+        match val {
+            "legacy" => Ok(Projection::Legacy),
+            "rectilinear" => Ok(Projection::Rectilinear),
+            "orthographic" => Ok(Projection::Orthographic),
+            "fisheye" => Ok(Projection::Fisheye),
+            "equirectangular" => Ok(Projection::Equirectangular),
+            _ => Err("invalid projection".to_owned()),
+        }
+    }
+}
+
+/* Where the image is seen from. `roll` is in degrees, clockwise around the
+ * viewing direction.
+ * With an `aperture` radius, rays start anywhere on a thin lens and only
+ * objects at `focus_distance` along the viewing direction are sharp. The
+ * lens is round, or a polygon when it has `blades`.
+ * Rays are cast at times between the opening and the closing of the
+ * `shutter`, as fractions of a frame, during which the camera moves by
+ * `velocity` per frame */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Camera {
+    pub position: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub roll: f64,
+    pub fov: Fov,
+    #[serde(default)]
+    pub projection: Projection,
+    /* 0 to see, at the look-at point, as wide as with a perspective */
+    #[serde(default)]
+    pub ortho_width: f64,
+    #[serde(default)]
+    pub aperture: f64,
+    #[serde(default = "default_focus_distance")]
+    pub focus_distance: f64,
+    #[serde(default)]
+    pub blades: u32,
+    #[serde(default)]
+    pub shutter: (f64, f64),
+    #[serde(default = "Vec3::origin")]
+    pub velocity: Vec3,
+    /* Offset of the center of the image along right and up, on the plane at
+     * distance 1, shifting what a perspective camera sees without turning
+     * it */
+    #[serde(default)]
+    pub shift: (f64, f64),
+}
+
+fn default_focus_distance() -> f64 {
+    1.
+}
+
+impl Camera {
+    /* Camera at `position` looking along `direction`, the way the eye used to
+     * be given */
+    pub fn new(position: Vec3, direction: &Vec3) -> Camera {
+        let look_at = position.addv(direction);
+        Camera {
+            position,
+            look_at,
+            up: Vec3::new(0., 1., 0.),
+            roll: 0.,
+            fov: Fov::Horizontal(90.),
+            projection: Projection::Legacy,
+            ortho_width: 0.,
+            aperture: 0.,
+            focus_distance: 1.,
+            blades: 0,
+            shutter: (0., 0.),
+            velocity: Vec3::origin(),
+            shift: (0., 0.),
+        }
+    }
+
+    pub fn direction(&self) -> Vec3 {
+        self.position.to(&self.look_at).normalize()
+    }
+
+    /* Orthonormal basis (right, up, forward) of the camera. When looking
+     * along the up vector, another up vector is picked so that the basis is
+     * never degenerate */
+    pub fn basis(&self) -> (Vec3, Vec3, Vec3) {
+        let forward = self.direction();
+        let mut right = self.up.cross_product(&forward);
+        if right.length_sq() < 1e-12 {
+            let fallback = if forward.y.abs() < 0.9 {
+                Vec3::new(0., 1., 0.)
+            } else {
+                Vec3::new(0., 0., 1.)
+            };
+            right = fallback.cross_product(&forward);
+        }
+        let right = right.normalize();
+        let up = forward.cross_product(&right).normalize();
+
+        let (sin, cos) = self.roll.to_radians().sin_cos();
+        let rolled_right = Vec3::new(
+            cos * right.x - sin * up.x,
+            cos * right.y - sin * up.y,
+            cos * right.z - sin * up.z,
+        );
+        let rolled_up = Vec3::new(
+            sin * right.x + cos * up.x,
+            sin * right.y + cos * up.y,
+            sin * right.z + cos * up.z,
+        );
+        (rolled_right, rolled_up, forward)
+    }
+
+    /* Half width and half height of what an orthographic camera sees */
+    pub fn ortho_extent(&self, aspect_ratio: f64) -> (f64, f64) {
+        let half_width = if self.ortho_width > 0. {
+            self.ortho_width / 2.
+        } else {
+            let distance = self.position.length_sq_to(&self.look_at).sqrt();
+            self.fov.plane(aspect_ratio).0 * distance
+        };
+        (half_width, half_width / aspect_ratio)
+    }
+
+    /* Look at the center of `bounds`, along the same direction, from where
+     * all of it is in the field of view. An orthographic camera is widened
+     * to see all of it instead, from just outside of it */
+    pub fn frame(&mut self, bounds: &Aabb, aspect_ratio: f64) {
+        let (right, up, forward) = self.basis();
+        let center = bounds.center();
+        if self.projection == Projection::Orthographic {
+            let corners: Vec<Vec3> = bounds.corners().iter().map(|p| center.to(p)).collect();
+            let extent = |axis: &Vec3| {
+                corners
+                    .iter()
+                    .map(|p| p.dot_product(axis).abs())
+                    .fold(0., f64::max)
+            };
+            let half_width = extent(&right).max(extent(&up) * aspect_ratio);
+            let distance = extent(&forward) + 0.01 * bounds.radius();
+            self.ortho_width = 2. * half_width;
+            self.position = center.translate(&forward, -distance);
+            self.look_at = center;
+            self.focus_distance = distance;
+            return;
+        }
+        let tangents = match self.projection {
+            Projection::Rectilinear => Some(self.fov.plane(aspect_ratio)),
+            Projection::Legacy => {
+                let (h, v) = self.fov.angles(aspect_ratio);
+                Some(((h / 2.).tan(), (v / 2.).tan()))
+            }
+            _ => None,
+        };
+        let distance = match tangents {
+            /* the closest distance at which every corner is within the
+             * image */
+            Some((tan_h, tan_v)) => bounds
+                .corners()
+                .iter()
+                .map(|p| {
+                    let p = center.to(p);
+                    let x = p.dot_product(&right).abs() / tan_h;
+                    let y = p.dot_product(&up).abs() / tan_v;
+                    x.max(y) - p.dot_product(&forward)
+                })
+                .fold(0., f64::max),
+            /* wide views, see the sphere around the bounds */
+            None => {
+                let (h, v) = self.fov.angles(aspect_ratio);
+                let half_angle = (h.min(v) / 2.).min(PI / 2.);
+                bounds.radius() / half_angle.sin()
+            }
+        };
+        self.position = center.translate(&forward, -distance);
+        self.look_at = center;
+        self.focus_distance = distance;
+    }
+
+    pub fn has_motion_blur(&self) -> bool {
+        self.shutter.1 > self.shutter.0
+    }
+    /* Time at which a ray is cast, for a sample of [0,1) */
+    pub fn time(&self, u: f64) -> f64 {
+        self.shutter.0 + u * (self.shutter.1 - self.shutter.0)
+    }
+
+    /* Point of the lens, relative to its center, for a sample of [0,1)² */
+    pub fn lens_offset(&self, u: f64, v: f64) -> (f64, f64) {
+        let (x, y) = if self.blades < 3 {
+            concentric_disk(u, v)
+        } else {
+            /* pick a triangle of the polygon, then a point in it */
+            let n = f64::from(self.blades);
+            let k = (u * n).floor().min(n - 1.);
+            let u = u * n - k;
+            let a0 = 2. * PI * k / n + PI / 2.;
+            let a1 = 2. * PI * (k + 1.) / n + PI / 2.;
+            let r = u.sqrt();
+            (
+                r * ((1. - v) * a0.cos() + v * a1.cos()),
+                r * ((1. - v) * a0.sin() + v * a1.sin()),
+            )
+        };
+        (self.aperture * x, self.aperture * y)
+    }
+}
+
+/* Map [0,1)² on the unit disk, keeping strata adjacent (Shirley & Chiu) */
+fn concentric_disk(u: f64, v: f64) -> (f64, f64) {
+    let a = 2. * u - 1.;
+    let b = 2. * v - 1.;
+    if a == 0. && b == 0. {
+        return (0., 0.);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4. * (b / a))
+    } else {
+        (b, PI / 2. - PI / 4. * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basis_is_orthonormal_when_looking_up() {
+        let mut camera = Camera::new(Vec3::origin(), &Vec3::new(0., 1., 0.));
+        camera.roll = 30.;
+        let (r, u, f) = camera.basis();
+        for v in &[&r, &u, &f] {
+            assert!((v.length_sq() - 1.).abs() < 1e-9);
+        }
+        assert!(r.dot_product(&u).abs() < 1e-9);
+        assert!(r.dot_product(&f).abs() < 1e-9);
+        assert!(u.dot_product(&f).abs() < 1e-9);
+    }
+
+    #[test]
+    fn framed_bounds_fill_the_image() {
+        let bounds = Aabb {
+            min: Vec3::new(-3., 0., 2.),
+            max: Vec3::new(5., 4., 7.),
+        };
+        let mut camera = Camera::new(Vec3::new(10., 10., 10.), &Vec3::new(-1., -0.5, -1.));
+        camera.projection = Projection::Rectilinear;
+        camera.fov = Fov::Vertical(40.);
+        let aspect_ratio = 2.;
+        let direction = camera.direction();
+        camera.frame(&bounds, aspect_ratio);
+        assert!(camera.direction().to(&direction).length_sq() < 1e-18);
+
+        let (tan_h, tan_v) = camera.fov.plane(aspect_ratio);
+        let (right, up, forward) = camera.basis();
+        let mut widest: f64 = 0.;
+        for p in bounds.corners() {
+            let p = camera.position.to(&p);
+            let depth = p.dot_product(&forward);
+            assert!(depth > 0.);
+            let x = p.dot_product(&right).abs() / depth / tan_h;
+            let y = p.dot_product(&up).abs() / depth / tan_v;
+            assert!(x <= 1. + 1e-9 && y <= 1. + 1e-9);
+            widest = widest.max(x).max(y);
+        }
+        assert!((widest - 1.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn framed_bounds_fill_orthographic_images() {
+        let bounds = Aabb {
+            min: Vec3::new(-3., 0., 2.),
+            max: Vec3::new(5., 4., 7.),
+        };
+        let mut camera = Camera::new(Vec3::new(10., 10., 10.), &Vec3::new(-1., -0.5, -1.));
+        camera.projection = Projection::Orthographic;
+        let aspect_ratio = 0.5;
+        camera.frame(&bounds, aspect_ratio);
+
+        let (half_width, half_height) = camera.ortho_extent(aspect_ratio);
+        let (right, up, forward) = camera.basis();
+        let mut widest: f64 = 0.;
+        for p in bounds.corners() {
+            let p = camera.position.to(&p);
+            assert!(p.dot_product(&forward) > 0.);
+            let x = p.dot_product(&right).abs() / half_width;
+            let y = p.dot_product(&up).abs() / half_height;
+            assert!(x <= 1. + 1e-9 && y <= 1. + 1e-9);
+            widest = widest.max(x).max(y);
+        }
+        assert!((widest - 1.).abs() < 1e-9);
+        /* the eye is close to the box */
+        let distance = camera.position.length_sq_to(&bounds.center()).sqrt();
+        assert!(distance < 1.05 * bounds.radius());
+    }
+
+    #[test]
+    fn lens_samples_stay_on_the_lens() {
+        let mut camera = Camera::new(Vec3::origin(), &Vec3::new(0., 0., 1.));
+        camera.aperture = 0.5;
+        for blades in &[0, 5] {
+            camera.blades = *blades;
+            for i in 0..32 {
+                for j in 0..32 {
+                    let (x, y) = camera.lens_offset(f64::from(i) / 32., f64::from(j) / 32.);
+                    assert!(x * x + y * y <= 0.25 + 1e-12);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,569 @@
+use crate::camera::Camera;
+use crate::maths::Vec3;
+use crate::raytracer::Screen;
+use crate::sampler::SamplerKind;
+use crate::tile::Tile;
+use image::{Rgba, RgbaImage};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/* Darkest luminance considered when computing the relative noise */
+const NOISE_MIN_LUMINANCE: f64 = 0.01;
+
+const MAGIC: &[u8; 8] = b"RTCKPT\x00\x04";
+
+/* Size of a serialized PixelAccumulator */
+const PIXEL_SIZE: usize = 44;
+
+/* Largest header a checkpoint can have, its JSON being a few hundred bytes */
+const MAX_HEADER_SIZE: usize = 1 << 20;
+
+/* What a checkpoint was rendered from. Resuming with anything else would
+ * mix two different renders */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CheckpointHeader {
+    pub scene_hash: u64,
+    pub camera: Camera,
+    pub screen: Screen,
+    pub region: Tile,
+    pub max_samples: u64,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub with_lambertian: bool,
+    pub with_shadows: bool,
+    /* Whether the samples come from several seeds, in which case no more
+     * can be added */
+    #[serde(default)]
+    pub stacked: bool,
+}
+impl CheckpointHeader {
+    /* Reasons why a render described by `other` can not continue this
+     * checkpoint. The sample count can change between runs */
+    pub fn mismatches(&self, other: &CheckpointHeader) -> Vec<String> {
+        let mut v = Vec::new();
+        if self.scene_hash != other.scene_hash {
+            v.push("scene differs".to_owned());
+        }
+        if self.camera != other.camera {
+            v.push(format!("camera was {:?}", self.camera));
+        }
+        if self.screen != other.screen {
+            v.push(format!(
+                "geometry was {}x{}",
+                self.screen.width, self.screen.height
+            ));
+        }
+        if self.region != other.region {
+            v.push(format!("region was {}", self.region));
+        }
+        if self.seed != other.seed {
+            v.push(format!("seed was {}", self.seed));
+        }
+        if self.sampler != other.sampler {
+            v.push(format!("sampler was {:?}", self.sampler));
+        }
+        if self.with_lambertian != other.with_lambertian {
+            v.push(format!("lambertians were {}", self.with_lambertian));
+        }
+        if self.with_shadows != other.with_shadows {
+            v.push(format!("shadows were {}", self.with_shadows));
+        }
+        if self.stacked != other.stacked {
+            v.push(format!("stacked was {}", self.stacked));
+        }
+        v
+    }
+
+    /* Only read the header of a checkpoint */
+    pub fn read(path: &Path) -> Result<CheckpointHeader, String> {
+        let f =
+            File::open(path).map_err(|why| format!("couldn't open {}: {}", path.display(), why))?;
+        let mut reader = BufReader::new(f);
+        read_header(&mut reader)
+            .map(|(header, _)| header)
+            .map_err(|why| format!("couldn't read checkpoint {}: {}", path.display(), why))
+    }
+}
+
+/* Header of a checkpoint, and the number of bytes it takes */
+fn read_header<R: Read>(reader: &mut R) -> Result<(CheckpointHeader, u64), String> {
+    let mut read = |buf: &mut [u8]| reader.read_exact(buf).map_err(|why| why.to_string());
+    let mut magic = [0u8; 8];
+    read(&mut magic)?;
+    if magic[..6] != MAGIC[..6] {
+        return Err("not a checkpoint file".to_owned());
+    }
+    if magic != *MAGIC {
+        return Err("unsupported checkpoint version".to_owned());
+    }
+    let mut b4 = [0u8; 4];
+    read(&mut b4)?;
+    let len = u32::from_le_bytes(b4) as usize;
+    if len > MAX_HEADER_SIZE {
+        return Err(format!("header of {} bytes", len));
+    }
+    let mut json = vec![0u8; len];
+    read(&mut json)?;
+    let header = serde_json::from_slice(&json).map_err(|why| why.to_string())?;
+    Ok((header, (MAGIC.len() + 4 + len) as u64))
+}
+
+/* Sums of the samples of a pixel, kept so that more samples can be added
+ * later on. The luminance is kept as its mean and the sum of the squared
+ * differences to it (Welford), which stay accurate however many samples
+ * there are */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PixelAccumulator {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub lum_mean: f64,
+    pub lum_m2: f64,
+    pub n: u32,
+}
+impl PixelAccumulator {
+    pub fn push(&mut self, c: &Vec3) {
+        let l = c.luminance();
+        self.r += c.x;
+        self.g += c.y;
+        self.b += c.z;
+        self.n += 1;
+        let delta = l - self.lum_mean;
+        self.lum_mean += delta / f64::from(self.n);
+        self.lum_m2 += delta * (l - self.lum_mean);
+    }
+    pub fn color(&self) -> Vec3 {
+        let n = f64::from(self.n.max(1));
+        Vec3::new(self.r / n, self.g / n, self.b / n)
+    }
+    /* Add the samples of `other` */
+    pub fn merge(&mut self, other: &PixelAccumulator) {
+        if other.n == 0 {
+            return;
+        }
+        let (na, nb) = (f64::from(self.n), f64::from(other.n));
+        let n = na + nb;
+        let delta = other.lum_mean - self.lum_mean;
+        self.lum_mean += delta * nb / n;
+        self.lum_m2 += other.lum_m2 + delta * delta * na * nb / n;
+        self.r += other.r;
+        self.g += other.g;
+        self.b += other.b;
+        self.n += other.n;
+    }
+    /* Relative standard error of the mean luminance */
+    pub fn noise(&self) -> f64 {
+        if self.n < 2 {
+            return f64::INFINITY;
+        }
+        let n = f64::from(self.n);
+        let variance = self.lum_m2.max(0.) / (n - 1.);
+        (variance / n).sqrt() / self.lum_mean.max(NOISE_MIN_LUMINANCE)
+    }
+}
+
+/* Float accumulation buffer of a region of the image. Pixels are
+ * addressed with their coordinates in the whole image */
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    pub region: Tile,
+    pub pixels: Vec<PixelAccumulator>,
+}
+impl Accumulator {
+    pub fn new(region: &Tile) -> Accumulator {
+        let len = (region.width as usize) * (region.height as usize);
+        Accumulator {
+            region: region.clone(),
+            pixels: vec![PixelAccumulator::default(); len],
+        }
+    }
+    fn index(&self, x: u32, y: u32) -> usize {
+        assert!(self.region.contains(x, y));
+        ((y - self.region.y) as usize) * (self.region.width as usize)
+            + ((x - self.region.x) as usize)
+    }
+    pub fn get(&self, x: u32, y: u32) -> &PixelAccumulator {
+        &self.pixels[self.index(x, y)]
+    }
+    pub fn get_mut(&mut self, x: u32, y: u32) -> &mut PixelAccumulator {
+        let idx = self.index(x, y);
+        &mut self.pixels[idx]
+    }
+    /* Copy of the pixels of `tile` */
+    pub fn extract(&self, tile: &Tile) -> Accumulator {
+        let mut part = Accumulator::new(tile);
+        for y in tile.y..tile.y + tile.height {
+            for x in tile.x..tile.x + tile.width {
+                *part.get_mut(x, y) = self.get(x, y).clone();
+            }
+        }
+        part
+    }
+    /* Overwrite pixels with those of `part` */
+    pub fn paste(&mut self, part: &Accumulator) {
+        let tile = &part.region;
+        for y in tile.y..tile.y + tile.height {
+            for x in tile.x..tile.x + tile.width {
+                *self.get_mut(x, y) = part.get(x, y).clone();
+            }
+        }
+    }
+    pub fn nb_samples(&self) -> u64 {
+        self.pixels.iter().map(|p| u64::from(p.n)).sum()
+    }
+
+    /* Image of the whole screen. Pixels that have not been sampled yet, or
+     * are out of the region, are left transparent magenta */
+    pub fn to_image(&self, screen: &Screen) -> RgbaImage {
+        let undone_color = Rgba([255u8, 0u8, 255u8, 0u8]);
+        let mut buf = RgbaImage::new(screen.width, screen.height);
+        for (x, y, pixel) in buf.enumerate_pixels_mut() {
+            *pixel = if self.region.contains(x, y) && self.get(x, y).n > 0 {
+                self.get(x, y).color().into()
+            } else {
+                undone_color
+            };
+        }
+        buf
+    }
+
+    /* Checkpoint file stored next to the rendered image */
+    pub fn path_for(pngpath: &str) -> PathBuf {
+        PathBuf::from(format!("{}.ckpt", pngpath))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * PIXEL_SIZE);
+        for p in &self.pixels {
+            bytes.extend_from_slice(&p.r.to_le_bytes());
+            bytes.extend_from_slice(&p.g.to_le_bytes());
+            bytes.extend_from_slice(&p.b.to_le_bytes());
+            bytes.extend_from_slice(&p.lum_mean.to_le_bytes());
+            bytes.extend_from_slice(&p.lum_m2.to_le_bytes());
+            bytes.extend_from_slice(&p.n.to_le_bytes());
+        }
+        bytes
+    }
+    pub fn from_bytes(region: &Tile, bytes: &[u8]) -> Result<Accumulator, String> {
+        let mut acc = Accumulator::new(region);
+        if bytes.len() != acc.pixels.len() * PIXEL_SIZE {
+            return Err(format!("expected {} pixels", acc.pixels.len()));
+        }
+        for (p, b) in acc.pixels.iter_mut().zip(bytes.chunks(PIXEL_SIZE)) {
+            let f = |i: usize| {
+                let mut b8 = [0u8; 8];
+                b8.copy_from_slice(&b[i..i + 8]);
+                f64::from_le_bytes(b8)
+            };
+            *p = PixelAccumulator {
+                r: f(0),
+                g: f(8),
+                b: f(16),
+                lum_mean: f(24),
+                lum_m2: f(32),
+                n: u32::from_le_bytes([b[40], b[41], b[42], b[43]]),
+            };
+        }
+        Ok(acc)
+    }
+
+    pub fn load(path: &Path) -> Result<(CheckpointHeader, Accumulator), String> {
+        let f =
+            File::open(path).map_err(|why| format!("couldn't open {}: {}", path.display(), why))?;
+        let file_size = f.metadata().map(|m| m.len()).unwrap_or(0);
+        let mut reader = BufReader::new(f);
+        let mut read = || -> Result<(CheckpointHeader, Accumulator), String> {
+            let (header, header_size) = read_header(&mut reader)?;
+            /* the region says how many pixels follow, check the file has
+             * them before allocating */
+            let size = header
+                .region
+                .nb_pixels()
+                .checked_mul(PIXEL_SIZE as u64)
+                .filter(|&size| header_size + size == file_size)
+                .ok_or_else(|| format!("truncated or corrupt, region {}", header.region))?;
+            let mut bytes = vec![0u8; size as usize];
+            reader
+                .read_exact(&mut bytes)
+                .map_err(|why| why.to_string())?;
+            let acc = Accumulator::from_bytes(&header.region, &bytes)?;
+            Ok((header, acc))
+        };
+        read().map_err(|why| format!("couldn't read checkpoint {}: {}", path.display(), why))
+    }
+
+    /* Written to a temporary file first, so that an interruption never
+     * leaves a truncated checkpoint behind */
+    pub fn save(&self, header: &CheckpointHeader, path: &Path) {
+        assert!(header.region == self.region);
+        let tmp = path.with_extension("ckpt.tmp");
+        let display = tmp.display();
+        let f = match File::create(&tmp) {
+            Err(why) => panic!("couldn't create {}: {}", display, why),
+            Ok(file) => file,
+        };
+        let mut w = BufWriter::new(f);
+        let mut write = |buf: &[u8]| {
+            if let Err(why) = w.write_all(buf) {
+                panic!("couldn't write {}: {}", display, why)
+            }
+        };
+        let json = match serde_json::to_vec(header) {
+            Err(why) => panic!("couldn't write {}: {}", display, why),
+            Ok(json) => json,
+        };
+        write(MAGIC);
+        write(&(json.len() as u32).to_le_bytes());
+        write(&json);
+        write(&self.to_bytes());
+        if let Err(why) = w.flush() {
+            panic!("couldn't write {}: {}", display, why)
+        }
+        drop(w);
+        if let Err(why) = fs::rename(&tmp, path) {
+            panic!("couldn't create {}: {}", path.display(), why)
+        }
+    }
+}
+
+/* Accumulator continuing the checkpoint at `path`, if any */
+pub fn resume(path: &Path, header: &CheckpointHeader) -> Result<Accumulator, String> {
+    if !path.exists() {
+        return Ok(Accumulator::new(&header.region));
+    }
+    let (previous, acc) = Accumulator::load(path)?;
+    let mismatches = previous.mismatches(header);
+    if !mismatches.is_empty() {
+        return Err(format!(
+            "{} was rendered with other settings: {}",
+            path.display(),
+            mismatches.join(", ")
+        ));
+    }
+    if previous.max_samples != header.max_samples {
+        info!(
+            "resuming render from {} to {} samples per pixel",
+            previous.max_samples, header.max_samples
+        );
+    }
+    Ok(acc)
+}
+
+/* Assemble checkpoints of disjoint regions of the same render into a
+ * checkpoint of the whole image */
+pub fn merge(
+    parts: Vec<(CheckpointHeader, Accumulator)>,
+) -> Result<(CheckpointHeader, Accumulator), String> {
+    if parts.is_empty() {
+        return Err("nothing to merge".to_owned());
+    }
+    let mut header = parts[0].0.clone();
+    header.region = Tile {
+        x: 0,
+        y: 0,
+        width: header.screen.width,
+        height: header.screen.height,
+    };
+    for (i, (h, _)) in parts.iter().enumerate() {
+        let mut h = h.clone();
+        h.region = header.region.clone();
+        let mismatches = h.mismatches(&header);
+        if !mismatches.is_empty() {
+            return Err(format!(
+                "part {} does not belong to the same render: {}",
+                i + 1,
+                mismatches.join(", ")
+            ));
+        }
+        if h.max_samples != header.max_samples {
+            warn!(
+                "parts were rendered with {} and {} samples per pixel",
+                header.max_samples, h.max_samples
+            );
+            header.max_samples = header.max_samples.max(h.max_samples);
+        }
+    }
+    for (h, _) in parts.iter() {
+        if !h.region.fits_in(header.screen.width, header.screen.height) {
+            return Err(format!("region {} is out of the image", h.region));
+        }
+    }
+    for (i, (a, _)) in parts.iter().enumerate() {
+        for (b, _) in parts.iter().skip(i + 1) {
+            if a.region.intersects(&b.region) {
+                return Err(format!("regions {} and {} overlap", a.region, b.region));
+            }
+        }
+    }
+
+    let mut acc = Accumulator::new(&header.region);
+    for (_, part) in parts.iter() {
+        acc.paste(part);
+    }
+    let missing = acc.pixels.iter().filter(|p| p.n == 0).count();
+    if missing > 0 {
+        return Err(format!("{} pixels have not been rendered", missing));
+    }
+    Ok((header, acc))
+}
+
+/* Combine renders of the same image made with different seeds, each pixel
+ * getting the samples of every render */
+pub fn stack(
+    parts: Vec<(CheckpointHeader, Accumulator)>,
+) -> Result<(CheckpointHeader, Accumulator), String> {
+    let mut parts = parts.into_iter();
+    let (mut header, mut acc) = match parts.next() {
+        Some(p) => p,
+        None => return Err("nothing to stack".to_owned()),
+    };
+    let mut seeds = vec![header.seed];
+    for (i, (h, part)) in parts.enumerate() {
+        let mut same_seed = h.clone();
+        same_seed.seed = header.seed;
+        same_seed.stacked = header.stacked;
+        let mismatches = same_seed.mismatches(&header);
+        if !mismatches.is_empty() {
+            return Err(format!(
+                "render {} is not of the same image: {}",
+                i + 2,
+                mismatches.join(", ")
+            ));
+        }
+        if seeds.contains(&h.seed) {
+            return Err(format!(
+                "render {} has the same seed {} as another one",
+                i + 2,
+                h.seed
+            ));
+        }
+        seeds.push(h.seed);
+        header.max_samples += h.max_samples;
+        for (p, q) in acc.pixels.iter_mut().zip(part.pixels.iter()) {
+            p.merge(q);
+        }
+    }
+    header.stacked |= seeds.len() > 1;
+    Ok((header, acc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stack_weights_by_sample_count() {
+        let region = Tile {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let header = |seed| CheckpointHeader {
+            scene_hash: 1,
+            camera: Camera::new(Vec3::origin(), &Vec3::new(0., 0., 1.)),
+            screen: Screen {
+                width: 1,
+                height: 1,
+            },
+            region: region.clone(),
+            max_samples: 4,
+            seed,
+            sampler: SamplerKind::Sobol,
+            with_lambertian: true,
+            with_shadows: true,
+            stacked: false,
+        };
+        let mut a = Accumulator::new(&region);
+        a.get_mut(0, 0).push(&Vec3::new(1., 1., 1.));
+        let mut b = Accumulator::new(&region);
+        for _ in 0..3 {
+            b.get_mut(0, 0).push(&Vec3::new(0., 0., 0.));
+        }
+        let (h, acc) = stack(vec![(header(1), a.clone()), (header(2), b.clone())]).unwrap();
+        assert_eq!(h.max_samples, 8);
+        /* a stacked render can not be resumed with a single seed */
+        assert!(h.stacked);
+        assert!(!h.mismatches(&header(1)).is_empty());
+        assert_eq!(acc.get(0, 0).n, 4);
+        assert_eq!(acc.get(0, 0).color(), Vec3::new(0.25, 0.25, 0.25));
+        assert!(stack(vec![(header(1), a.clone()), (header(1), a.clone())]).is_err());
+        let (h, acc) = stack(vec![(header(1), a.clone()), (header(2), b), (header(3), a)]).unwrap();
+        assert_eq!(h.max_samples, 12);
+        assert!(h.stacked);
+        assert_eq!(acc.get(0, 0).n, 5);
+        assert_eq!(acc.get(0, 0).color(), Vec3::new(0.4, 0.4, 0.4));
+    }
+
+    #[test]
+    fn noise_of_bright_pixels_survives_many_samples() {
+        /* luminance 1000 ± 1, pushed then merged from two halves */
+        let mut p = PixelAccumulator::default();
+        let mut q = PixelAccumulator::default();
+        for i in 0..200_000 {
+            let l = 1000. + if i % 2 == 0 { 1. } else { -1. };
+            let c = Vec3::new(l, l, l);
+            p.push(&c);
+            if i < 100_000 {
+                q.push(&c);
+            }
+        }
+        let mut r = PixelAccumulator::default();
+        for i in 100_000..200_000 {
+            let l = 1000. + if i % 2 == 0 { 1. } else { -1. };
+            r.push(&Vec3::new(l, l, l));
+        }
+        q.merge(&r);
+        let expected = (1. / 200_000_f64).sqrt() / 1000.;
+        for acc in &[p, q] {
+            assert!((acc.noise() / expected - 1.).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn checkpoint_roundtrip() {
+        let region = Tile {
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 2,
+        };
+        let mut acc = Accumulator::new(&region);
+        acc.get_mut(1, 1).push(&Vec3::new(0.25, 0.5, 1.));
+        acc.get_mut(1, 1).push(&Vec3::new(0.75, 0.5, 0.));
+        acc.get_mut(2, 0).push(&Vec3::new(1., 1., 1.));
+        let header = CheckpointHeader {
+            scene_hash: 1234,
+            camera: Camera::new(Vec3::origin(), &Vec3::new(0., 0., 1.)),
+            screen: Screen {
+                width: 3,
+                height: 2,
+            },
+            region,
+            max_samples: 8,
+            seed: 42,
+            sampler: SamplerKind::Sobol,
+            with_lambertian: true,
+            with_shadows: false,
+            stacked: false,
+        };
+        let path = std::env::temp_dir().join("rt-test-roundtrip.ckpt");
+        acc.save(&header, &path);
+        let (h, loaded) = Accumulator::load(&path).unwrap();
+        /* a truncated checkpoint is an error */
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(Accumulator::load(&path).is_err());
+        fs::remove_file(&path).ok();
+        assert!(h.mismatches(&header).is_empty());
+        let mut other = header.clone();
+        other.seed = 43;
+        other.max_samples = 16;
+        assert_eq!(h.mismatches(&other).len(), 1);
+        assert!(loaded.region == acc.region);
+        assert!(loaded.pixels == acc.pixels);
+        assert_eq!(loaded.get(1, 1).color().x, 0.5);
+        assert_eq!(loaded.nb_samples(), 3);
+    }
+}
@@ -0,0 +1,378 @@
+/* Render farm: a coordinator hands out tiles of a render to workers
+ * connected over TCP.
+ *
+ * Every message is framed as a little endian u32 length followed by a JSON
+ * encoded `Message`, then a u32 length followed by a binary payload holding
+ * the scene with the job, and pixel accumulators when the message carries a
+ * tile. */
+
+use crate::camera::Camera;
+use crate::checkpoint::{self, Accumulator};
+use crate::raytracer::{RayCtx, SamplingSettings, Screen};
+use crate::scene::Scene;
+use crate::tile::{generate_tiles, Tile, TileSettings};
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/* Largest frame accepted, scenes with big meshes included */
+const MAX_FRAME: usize = 1 << 28;
+
+/* How workers render the scene sent along */
+#[derive(Serialize, Deserialize, Debug)]
+struct Job {
+    camera: Camera,
+    screen: Screen,
+    with_lambertian: bool,
+    with_shadows: bool,
+    sampling: SamplingSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Message {
+    /* coordinator → worker, first message */
+    Job(Box<Job>),
+    /* coordinator → worker, with the current state of the tile */
+    Tile(Tile),
+    /* worker → coordinator, with the rendered tile */
+    Result(Tile),
+    /* coordinator → worker, nothing left to render */
+    Done,
+}
+
+fn send<W: Write>(w: &mut W, msg: &Message, payload: &[u8]) -> io::Result<()> {
+    let json = serde_json::to_vec(msg)?;
+    w.write_all(&(json.len() as u32).to_le_bytes())?;
+    w.write_all(&json)?;
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+fn recv<R: Read>(r: &mut R) -> io::Result<(Message, Vec<u8>)> {
+    let mut read_frame = || -> io::Result<Vec<u8>> {
+        let mut b4 = [0u8; 4];
+        r.read_exact(&mut b4)?;
+        let len = u32::from_le_bytes(b4) as usize;
+        if len > MAX_FRAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes", len),
+            ));
+        }
+        let mut buf = vec![0u8; len];
+        r.read_exact(&mut buf)?;
+        Ok(buf)
+    };
+    let json = read_frame()?;
+    let payload = read_frame()?;
+    let msg = serde_json::from_slice(&json)?;
+    Ok((msg, payload))
+}
+
+/* State shared by the threads talking to workers */
+struct Shared {
+    acc: Accumulator,
+    queue: VecDeque<Tile>,
+    in_flight: usize,
+    nb_tiles: usize,
+    nb_tiles_done: usize,
+}
+
+fn handle_worker(
+    stream: TcpStream,
+    job: &[u8],
+    shared: &Mutex<Shared>,
+    cond: &Condvar,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    info!("worker {} connected", peer);
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(job)?;
+    writer.flush()?;
+    loop {
+        let tile = {
+            let mut s = shared.lock().unwrap();
+            loop {
+                if stop.load(Ordering::SeqCst) {
+                    break None;
+                }
+                if let Some(t) = s.queue.pop_front() {
+                    s.in_flight += 1;
+                    break Some(t);
+                }
+                if s.in_flight == 0 {
+                    break None;
+                }
+                s = cond.wait(s).unwrap();
+            }
+        };
+        let tile = match tile {
+            Some(t) => t,
+            None => {
+                send(&mut writer, &Message::Done, &[])?;
+                info!("worker {} done", peer);
+                return Ok(());
+            }
+        };
+        let part = shared.lock().unwrap().acc.extract(&tile);
+        let rendered = send(&mut writer, &Message::Tile(tile.clone()), &part.to_bytes())
+            .and_then(|_| recv(&mut reader))
+            .and_then(|(msg, payload)| match msg {
+                Message::Result(ref t) if *t == tile => Accumulator::from_bytes(t, &payload)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected message",
+                )),
+            });
+        let mut s = shared.lock().unwrap();
+        s.in_flight -= 1;
+        match rendered {
+            Ok(part) => {
+                s.acc.paste(&part);
+                s.nb_tiles_done += 1;
+                print!("\r> tile {}/{}", s.nb_tiles_done, s.nb_tiles);
+                io::stdout().flush().ok();
+                cond.notify_all();
+            }
+            Err(why) => {
+                warn!("worker {} lost ({}), re-queuing tile {}", peer, why, tile);
+                s.queue.push_front(tile);
+                cond.notify_all();
+                return Err(why);
+            }
+        }
+    }
+}
+
+/* Coordinate the render of `scene_json` by workers connecting to
+ * `listener`, until every tile has been rendered. A worker not answering
+ * within `timeout` is considered lost and its tile given to another one */
+pub fn serve(
+    listener: TcpListener,
+    scene_json: &[u8],
+    ray_ctx: &RayCtx,
+    sampling: &SamplingSettings,
+    tiling: &TileSettings,
+    pngpath: &str,
+    timeout: Duration,
+) -> Result<(), String> {
+    let stop = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&stop)).ok();
+    signal_hook::flag::register(signal_hook::SIGTERM, Arc::clone(&stop)).ok();
+    let scene = Scene::from_json(scene_json)?;
+    let ckpt_path = Accumulator::path_for(pngpath);
+    let header = ray_ctx.checkpoint_header(&scene, sampling, tiling);
+    let acc = checkpoint::resume(&ckpt_path, &header)?;
+    let queue: VecDeque<Tile> = generate_tiles(tiling)
+        .into_iter()
+        .filter(|t| {
+            acc.extract(t)
+                .pixels
+                .iter()
+                .any(|p| !sampling.is_pixel_done(p))
+        })
+        .collect();
+    info!("{} tiles to render", queue.len());
+
+    let job = Message::Job(Box::new(Job {
+        camera: ray_ctx.camera.clone(),
+        screen: ray_ctx.screen.clone(),
+        with_lambertian: ray_ctx.with_lambertian,
+        with_shadows: ray_ctx.with_shadows,
+        sampling: sampling.clone(),
+    }));
+    let mut job_frame = Vec::new();
+    send(&mut job_frame, &job, scene_json).map_err(|e| e.to_string())?;
+
+    let shared = Mutex::new(Shared {
+        acc,
+        nb_tiles: queue.len(),
+        queue,
+        in_flight: 0,
+        nb_tiles_done: 0,
+    });
+    let cond = Condvar::new();
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+    thread::scope(|scope| loop {
+        {
+            let s = shared.lock().unwrap();
+            if (s.queue.is_empty() && s.in_flight == 0) || stop.load(Ordering::SeqCst) {
+                cond.notify_all();
+                break;
+            }
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false).ok();
+                stream.set_read_timeout(Some(timeout)).ok();
+                stream.set_write_timeout(Some(timeout)).ok();
+                let (job, shared, cond, stop) = (&job_frame, &shared, &cond, &*stop);
+                scope.spawn(move || handle_worker(stream, job, shared, cond, stop));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => warn!("couldn't accept worker: {}", e),
+        }
+    });
+
+    let s = shared.into_inner().unwrap();
+    s.acc.to_image(&ray_ctx.screen).save(pngpath).ok();
+    s.acc.save(&header, &ckpt_path);
+    Ok(())
+}
+
+/* Render tiles handed out by the coordinator at `addr`, giving up when it
+ * sends nothing for `timeout` */
+pub fn work(addr: &str, timeout: Duration) -> Result<(), String> {
+    let stream = TcpStream::connect(addr).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    let mut writer = BufWriter::new(stream);
+
+    let (scene, ray_ctx, sampling) = match recv(&mut reader).map_err(|e| e.to_string())? {
+        (Message::Job(job), scene_json) => {
+            let scene = Scene::from_json(&scene_json)?;
+            let ray_ctx = RayCtx::new(
+                &job.camera,
+                &job.screen,
+                job.with_lambertian,
+                job.with_shadows,
+            );
+            (scene, ray_ctx, job.sampling)
+        }
+        (msg, _) => return Err(format!("unexpected message {:?}", msg)),
+    };
+
+    loop {
+        match recv(&mut reader).map_err(|e| e.to_string())? {
+            (Message::Tile(tile), payload) => {
+                let mut part = Accumulator::from_bytes(&tile, &payload)?;
+                ray_ctx.render_tile(&scene, &sampling, &mut part, sampling.max_samples);
+                send(&mut writer, &Message::Result(tile), &part.to_bytes())
+                    .map_err(|e| e.to_string())?;
+            }
+            (Message::Done, _) => return Ok(()),
+            (msg, _) => return Err(format!("unexpected message {:?}", msg)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maths::Vec3;
+    use crate::object::{BaseObject, Plan, Sphere};
+    use crate::sampler::SamplerKind;
+    use crate::tile::TileOrder;
+    use image::Rgb;
+
+    #[test]
+    fn coordinator_requeues_tiles_of_lost_workers() {
+        let mut scene = Scene::new();
+        scene.add(BaseObject::Plan(Plan::new(
+            Vec3::origin(),
+            Vec3::new(0., 1., 0.),
+            Rgb([237, 201, 175]),
+        )));
+        scene.add(BaseObject::Sphere(Sphere::new(
+            Vec3::new(0., 1., 5.),
+            1.,
+            Rgb([0, 151, 0]),
+            true,
+        )));
+        let scene_json = serde_json::to_vec(&scene).unwrap();
+        let camera = Camera::new(Vec3::new(0., 1., 0.), &Vec3::new(0., 0., 1.));
+        let screen = Screen {
+            width: 10,
+            height: 6,
+        };
+        let ray_ctx = RayCtx::new(&camera, &screen, true, false);
+        let sampling = SamplingSettings {
+            min_samples: 2,
+            max_samples: 2,
+            noise_threshold: 0.,
+            pass_samples: 0,
+            sampler: SamplerKind::Sobol,
+            seed: 7,
+        };
+        let tiling = TileSettings {
+            size: 4,
+            order: TileOrder::Scanline,
+            region: Tile {
+                x: 0,
+                y: 0,
+                width: 10,
+                height: 6,
+            },
+        };
+        let path = std::env::temp_dir().join("rt-test-farm.png");
+        let pngpath = path.to_str().unwrap().to_owned();
+        let ckpt_path = Accumulator::path_for(&pngpath);
+        std::fs::remove_file(&ckpt_path).ok();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let coordinator = {
+            let (scene_json, pngpath) = (scene_json.clone(), pngpath.clone());
+            let (ray_ctx, sampling, tiling) = (
+                RayCtx::new(&camera, &screen, true, false),
+                sampling.clone(),
+                tiling.clone(),
+            );
+            thread::spawn(move || {
+                serve(
+                    listener,
+                    &scene_json,
+                    &ray_ctx,
+                    &sampling,
+                    &tiling,
+                    &pngpath,
+                    Duration::from_millis(500),
+                )
+            })
+        };
+
+        /* A worker that vanishes once given a tile */
+        {
+            let stream = TcpStream::connect(&addr).unwrap();
+            let mut reader = BufReader::new(stream);
+            let (msg, payload) = recv(&mut reader).unwrap();
+            assert!(matches!(msg, Message::Job(_)));
+            assert!(payload == scene_json);
+            let (msg, _) = recv(&mut reader).unwrap();
+            assert!(matches!(msg, Message::Tile(_)));
+        }
+        /* A worker that stalls once given a tile */
+        let stalled = TcpStream::connect(&addr).unwrap();
+        let mut reader = BufReader::new(stalled.try_clone().unwrap());
+        recv(&mut reader).unwrap();
+        assert!(matches!(recv(&mut reader).unwrap().0, Message::Tile(_)));
+        work(&addr, Duration::from_secs(10)).unwrap();
+        coordinator.join().unwrap().unwrap();
+        drop(stalled);
+
+        let (_, farmed) = Accumulator::load(&ckpt_path).unwrap();
+        std::fs::remove_file(&ckpt_path).ok();
+        std::fs::remove_file(&path).ok();
+        assert!(farmed.pixels.iter().all(|p| p.n == 2));
+
+        let mut local = Accumulator::new(&tiling.region);
+        ray_ctx.render_tile(&scene, &sampling, &mut local, 2);
+        assert!(local.pixels == farmed.pixels);
+
+        /* frames too big to be genuine are refused before being read */
+        assert!(recv(&mut &[0xff, 0xff, 0xff, 0xff][..]).is_err());
+    }
+}
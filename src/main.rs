@@ -17,23 +17,47 @@ extern crate signal_hook;
 #[macro_use]
 extern crate debug_macros;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use image::Rgb;
 use regex::Regex;
 
+mod animation;
+mod camera;
+mod checkpoint;
+mod farm;
 mod maths;
+mod obj;
 mod object;
 mod raytracer;
+mod sampler;
 mod scene;
+mod server;
+mod stereo;
+mod tile;
+mod turntable;
 
+use animation::{frame_path, Animation, Interpolation};
+use camera::{Camera, Fov, Projection};
+use checkpoint::{Accumulator, CheckpointHeader};
 use maths::Vec3;
+use obj::ObjModel;
 use object::{BaseObject, Plan};
-use raytracer::{Eye, RayCtx, Screen};
-use scene::Scene;
-use std::path::Path;
+use raytracer::{print_progress, suffixed_path, RayCtx, SamplingSettings, Screen};
+use sampler::SamplerKind;
+use scene::{Scene, SceneCamera};
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use stereo::{Stereo, StereoLayout};
+use tile::{partition, Tile, TileOrder, TileSettings};
+use turntable::{contact_sheet, Turntable};
 
 struct Preset {
-    eye: Eye,
+    camera: Camera,
     nb_samples: u64,
     screen: Screen,
 }
@@ -55,6 +79,54 @@ fn is_geometry(val: String) -> Result<(), String> {
     }
 }
 
+fn parse_region(val: &str) -> Result<Tile, String> {
+    let re = Regex::new(r"^(\d+),(\d+),(\d+)x(\d+)$").unwrap();
+    let c = re
+        .captures(val)
+        .ok_or_else(|| "invalid region".to_owned())?;
+    let get = |i| {
+        c.get(i)
+            .unwrap()
+            .as_str()
+            .parse::<u32>()
+            .map_err(|_| "invalid region".to_owned())
+    };
+    let region = Tile {
+        x: get(1)?,
+        y: get(2)?,
+        width: get(3)?,
+        height: get(4)?,
+    };
+    if !region.fits_in(u32::MAX, u32::MAX) {
+        return Err("region is empty or too large".to_owned());
+    }
+    Ok(region)
+}
+fn is_region(val: String) -> Result<(), String> {
+    match parse_region(&val) {
+        Err(s) => Err(s),
+        _ => Ok(()),
+    }
+}
+
+fn parse_partition(val: &str) -> Result<(u32, u32), String> {
+    let re = Regex::new(r"^(\d+)/(\d+)$").unwrap();
+    if let Some(c) = re.captures(val) {
+        let i: u32 = c.get(1).unwrap().as_str().parse::<u32>().unwrap();
+        let n: u32 = c.get(2).unwrap().as_str().parse::<u32>().unwrap();
+        if i < n {
+            return Ok((i, n));
+        }
+    }
+    Err("invalid partition, expecting i/n with i < n".to_owned())
+}
+fn is_partition(val: String) -> Result<(), String> {
+    match parse_partition(&val) {
+        Err(s) => Err(s),
+        _ => Ok(()),
+    }
+}
+
 fn parse_vec3(val: &str) -> Result<Vec3, String> {
     let re = Regex::new(
         r"\(([+-]?[0-9]+[.][0-9]*),[ ]+([+-]?[0-9]+[.][0-9]*),[ ]+([+-]?[0-9]+[.][0-9]*)\)",
@@ -111,13 +183,615 @@ fn is_sun(val: String) -> Result<(), String> {
     }
 }
 
+fn is_seed(val: String) -> Result<(), String> {
+    match val.parse::<u64>() {
+        Err(_) => Err("invalid seed".to_owned()),
+        _ => Ok(()),
+    }
+}
+/* Without --seed, reuse the seed of the checkpoint being resumed, if any */
+fn get_seed(m: &ArgMatches, checkpoint: Option<&CheckpointHeader>) -> u64 {
+    let seed = if m.is_present("seed") {
+        value_t!(m, "seed", u64).unwrap()
+    } else if let Some(header) = checkpoint {
+        header.seed
+    } else {
+        rand::random::<u64>()
+    };
+    info!("seed:{:?}", seed);
+    seed
+}
+
+fn is_angle(val: String) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(a) if a.is_finite() => Ok(()),
+        _ => Err("invalid angle".to_owned()),
+    }
+}
+fn is_length(val: String) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(l) if l >= 0. && l.is_finite() => Ok(()),
+        _ => Err("invalid length".to_owned()),
+    }
+}
+fn parse_shutter(val: &str) -> Result<(f64, f64), String> {
+    let mut it = val.split(',').map(|v| v.trim().parse::<f64>());
+    match (it.next(), it.next(), it.next()) {
+        (Some(Ok(open)), Some(Ok(close)), None)
+            if (0. ..=1.).contains(&open) && (open..=1.).contains(&close) =>
+        {
+            Ok((open, close))
+        }
+        _ => Err("shutter must be OPEN,CLOSE with 0 <= OPEN <= CLOSE <= 1".to_owned()),
+    }
+}
+fn is_shutter(val: String) -> Result<(), String> {
+    parse_shutter(&val).map(|_| ())
+}
+fn is_fps(val: String) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(f) if f > 0. && f.is_finite() => Ok(()),
+        _ => Err("invalid frame rate".to_owned()),
+    }
+}
+fn is_nb_views(val: String) -> Result<(), String> {
+    match val.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("expecting at least 1".to_owned()),
+    }
+}
+fn is_min_samples(val: String) -> Result<(), String> {
+    match val.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("expecting at least 1 sample".to_owned()),
+    }
+}
+fn is_noise_threshold(val: String) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(t) if t >= 0. && t.is_finite() => Ok(()),
+        _ => Err("invalid noise threshold".to_owned()),
+    }
+}
+fn is_pass_samples(val: String) -> Result<(), String> {
+    match val.parse::<u64>() {
+        Ok(_) => Ok(()),
+        _ => Err("invalid number of samples per pass".to_owned()),
+    }
+}
+fn is_tile_size(val: String) -> Result<(), String> {
+    match val.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("invalid tile size".to_owned()),
+    }
+}
+fn is_blades(val: String) -> Result<(), String> {
+    match val.parse::<u32>() {
+        Ok(n) if n == 0 || n >= 3 => Ok(()),
+        _ => Err("expecting 0 or at least 3 blades".to_owned()),
+    }
+}
+fn is_timeout(val: String) -> Result<(), String> {
+    match val.parse::<u64>() {
+        Ok(t) if t > 0 => Ok(()),
+        _ => Err("expecting a number of seconds".to_owned()),
+    }
+}
+fn is_crease_angle(val: String) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(a) if (0. ..=180.).contains(&a) => Ok(()),
+        _ => Err("crease angle must be between 0 and 180 degrees".to_owned()),
+    }
+}
+fn is_fov(val: String) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(a) if a > 0. && a <= 360. => Ok(()),
+        _ => Err("field of view must be between 0 and 360 degrees".to_owned()),
+    }
+}
+
+/* Camera arguments, on top of --eye-position and --eye-direction whose
+ * defaults depend on the command */
+fn camera_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(
+        Arg::with_name("look_at")
+            .long("look-at")
+            .takes_value(true)
+            .validator(is_vec3)
+            .help("point the eye looks at, instead of --eye-direction"),
+    )
+    .arg(
+        Arg::with_name("up")
+            .long("up")
+            .default_value("(0., 1., 0.)")
+            .validator(is_vec3)
+            .help("up direction of the world"),
+    )
+    .arg(
+        Arg::with_name("roll")
+            .long("roll")
+            .default_value("0")
+            .validator(is_angle)
+            .help("clockwise rotation of the eye around its direction, in degrees"),
+    )
+    .arg(
+        Arg::with_name("hfov")
+            .long("hfov")
+            .default_value("90")
+            .validator(is_fov)
+            .help("horizontal field of view, in degrees"),
+    )
+    .arg(
+        Arg::with_name("vfov")
+            .long("vfov")
+            .takes_value(true)
+            .validator(is_fov)
+            .help("vertical field of view, in degrees, instead of --hfov"),
+    )
+    .arg(
+        Arg::with_name("projection")
+            .long("projection")
+            .takes_value(true)
+            .possible_values(&[
+                "legacy",
+                "rectilinear",
+                "orthographic",
+                "fisheye",
+                "equirectangular",
+            ])
+            .default_value("legacy")
+            .help("how the scene is projected on the image"),
+    )
+    .arg(
+        Arg::with_name("ortho_width")
+            .long("ortho-width")
+            .takes_value(true)
+            .validator(is_length)
+            .help("width seen by an orthographic camera (default: as wide as --hfov at --look-at)"),
+    )
+}
+/* Camera given by the arguments. Starting from the `base` camera, such as
+ * one stored in the scene, only the arguments given on the command line
+ * override it */
+fn get_camera(m: &ArgMatches, base: Option<&Camera>) -> Camera {
+    let given = |name: &str| m.occurrences_of(name) > 0 || (base.is_none() && m.is_present(name));
+    let mut camera = match base {
+        Some(base) => base.clone(),
+        None => Camera::new(Vec3::origin(), &Vec3::new(0., 0., 1.)),
+    };
+    if given("eye_position") {
+        let position = parse_vec3(m.value_of("eye_position").unwrap()).unwrap();
+        let direction = camera.position.to(&camera.look_at);
+        camera.look_at = position.addv(&direction);
+        camera.position = position;
+    }
+    if given("eye_direction") {
+        let direction = parse_vec3(m.value_of("eye_direction").unwrap()).unwrap();
+        camera.look_at = camera.position.addv(&direction);
+    }
+    if let Some(look_at) = m.value_of("look_at") {
+        camera.look_at = parse_vec3(look_at).unwrap();
+    }
+    if given("up") {
+        camera.up = parse_vec3(m.value_of("up").unwrap()).unwrap();
+    }
+    if given("roll") {
+        camera.roll = value_t!(m, "roll", f64).unwrap();
+    }
+    if m.is_present("vfov") {
+        camera.fov = Fov::Vertical(value_t!(m, "vfov", f64).unwrap());
+    } else if given("hfov") {
+        camera.fov = Fov::Horizontal(value_t!(m, "hfov", f64).unwrap());
+    }
+    if given("projection") {
+        camera.projection = Projection::parse(m.value_of("projection").unwrap()).unwrap();
+    }
+    if let Some(width) = m.value_of("ortho_width") {
+        camera.ortho_width = width.parse::<f64>().unwrap();
+    }
+    if given("aperture") {
+        camera.aperture = value_t!(m, "aperture", f64).unwrap();
+        if !m.is_present("focus_distance") {
+            camera.focus_distance = camera.position.length_sq_to(&camera.look_at).sqrt();
+        }
+    }
+    if given("blades") {
+        camera.blades = value_t!(m, "blades", u32).unwrap();
+    }
+    if given("shutter") {
+        camera.shutter = parse_shutter(m.value_of("shutter").unwrap()).unwrap();
+    }
+    if given("camera_velocity") {
+        camera.velocity = parse_vec3(m.value_of("camera_velocity").unwrap()).unwrap();
+    }
+    if m.is_present("focus_distance") {
+        camera.focus_distance = value_t!(m, "focus_distance", f64).unwrap();
+    }
+    if camera.position == camera.look_at {
+        error!("the eye can not look at its own position");
+        process::exit(1);
+    }
+    if camera.up.length_sq() == 0. {
+        error!("the up direction can not be null");
+        process::exit(1);
+    }
+    let flat = match camera.projection {
+        Projection::Legacy | Projection::Rectilinear | Projection::Orthographic => true,
+        Projection::Fisheye | Projection::Equirectangular => false,
+    };
+    let fov = match camera.fov {
+        Fov::Horizontal(a) | Fov::Vertical(a) => a,
+    };
+    if flat && fov >= 180. {
+        error!("the field of view must be below 180 degrees with this projection");
+        process::exit(1);
+    }
+    info!("camera:{:?}", camera);
+    camera
+}
+
+/* Arguments shared by the commands rendering a scene */
+fn render_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    camera_args(cmd)
+        .arg(
+            Arg::with_name("aperture")
+                .long("aperture")
+                .default_value("0")
+                .validator(is_length)
+                .help("radius of the lens, 0 for a pinhole"),
+        )
+        .arg(
+            Arg::with_name("focus_distance")
+                .long("focus-distance")
+                .takes_value(true)
+                .validator(is_length)
+                .help("distance of the sharp plane (default: distance to --look-at)"),
+        )
+        .arg(
+            Arg::with_name("autofocus")
+                .long("autofocus")
+                .conflicts_with("focus_distance")
+                .help("focus on what is at the center of the image"),
+        )
+        .arg(
+            Arg::with_name("shutter")
+                .long("shutter")
+                .default_value("0,0")
+                .validator(is_shutter)
+                .help("OPEN,CLOSE times of the shutter, as fractions of a frame"),
+        )
+        .arg(
+            Arg::with_name("camera_velocity")
+                .long("camera-velocity")
+                .default_value("(0., 0., 0.)")
+                .validator(is_vec3)
+                .help("how far the eye moves during a frame"),
+        )
+        .arg(
+            Arg::with_name("blades")
+                .long("blades")
+                .default_value("0")
+                .validator(is_blades)
+                .help("number of aperture blades shaping the bokeh, 0 for a round one"),
+        )
+        .arg(
+            Arg::with_name("CFG")
+                .help("config file")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("PNG")
+                .help("png file to render to")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("samples")
+                .short("s")
+                .long("samples")
+                .takes_value(true)
+                .default_value("8")
+                .help("maximum number of rays to fire per pixel"),
+        )
+        .arg(
+            Arg::with_name("min_samples")
+                .long("min-samples")
+                .takes_value(true)
+                .validator(is_min_samples)
+                .help("minimum number of rays to fire per pixel (default: samples)"),
+        )
+        .arg(
+            Arg::with_name("noise_threshold")
+                .long("noise-threshold")
+                .takes_value(true)
+                .default_value("0.01")
+                .validator(is_noise_threshold)
+                .help("relative noise under which a pixel stops being sampled"),
+        )
+        .arg(
+            Arg::with_name("pass_samples")
+                .long("pass-samples")
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_pass_samples)
+                .help("render progressively, by passes of that many rays per pixel"),
+        )
+        .arg(
+            Arg::with_name("sampler")
+                .long("sampler")
+                .takes_value(true)
+                .possible_values(&["random", "stratified", "halton", "sobol"])
+                .default_value("sobol")
+                .help("how samples are distributed within a pixel"),
+        )
+        .arg(
+            Arg::with_name("eye_position")
+                .short("e")
+                .long("eye-position")
+                .default_value("(0., 0., -25.)")
+                .validator(is_vec3)
+                .help("position of the eye in the scene"),
+        )
+        .arg(
+            Arg::with_name("eye_direction")
+                .short("i")
+                .long("eye-direction")
+                .default_value("(0., 0., 1.)")
+                .validator(is_vec3)
+                .help("direction of the eye in the scene"),
+        )
+        .arg(
+            Arg::with_name("geometry")
+                .short("g")
+                .long("geometry")
+                .default_value("4096x2160")
+                .validator(is_geometry)
+                .help("size of the image that would be generated"),
+        )
+        .arg(
+            Arg::with_name("tile_size")
+                .long("tile-size")
+                .takes_value(true)
+                .default_value("32")
+                .validator(is_tile_size)
+                .help("size in pixels of the tiles rendered in parallel"),
+        )
+        .arg(
+            Arg::with_name("tile_order")
+                .long("tile-order")
+                .takes_value(true)
+                .possible_values(&["scanline", "spiral", "hilbert"])
+                .default_value("spiral")
+                .help("order in which tiles are rendered"),
+        )
+        .arg(
+            Arg::with_name("region")
+                .long("region")
+                .takes_value(true)
+                .validator(is_region)
+                .conflicts_with("tiles")
+                .help("only render the X,Y,WxH part of the image"),
+        )
+        .arg(
+            Arg::with_name("tiles")
+                .long("tiles")
+                .takes_value(true)
+                .validator(is_partition)
+                .help("only render band i of n bands of tiles (from 0)"),
+        )
+        .arg(
+            Arg::with_name("auto_frame")
+                .long("auto-frame")
+                .help("move the eye, along its direction, so that the whole scene is seen"),
+        )
+        .arg(
+            Arg::with_name("camera")
+                .long("camera")
+                .takes_value(true)
+                .help("name of the scene camera to render (default: the first one)"),
+        )
+        .arg(
+            Arg::with_name("restart")
+                .long("restart")
+                .help("discard any previous checkpoint of this render"),
+        )
+        .arg(
+            Arg::with_name("no_shadows")
+                .long("no-shadows")
+                .help("Do not render shadows"),
+        )
+        .arg(
+            Arg::with_name("no_lambertian")
+                .long("no-lambertians")
+                .help("Do not render lambertians"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .validator(is_seed)
+                .help("seed of the random generators (default: random)"),
+        )
+}
+
+/* The scene camera picked by --camera, or the default one */
+fn get_scene_camera<'a>(m: &ArgMatches, scene: &'a Scene) -> Option<&'a SceneCamera> {
+    let name = m.value_of("camera");
+    let camera = scene.camera(name);
+    if let (Some(name), None) = (name, camera) {
+        error!("the scene has no camera named {}", name);
+        process::exit(1);
+    }
+    camera
+}
+
+/* Checkpoints of a render to `pngpath` */
+fn checkpoint_paths(m: &ArgMatches, pngpath: &str) -> Vec<PathBuf> {
+    if m.is_present("stereo") {
+        ["left", "right"]
+            .iter()
+            .map(|eye| Accumulator::path_for(&Stereo::path_for(pngpath, eye)))
+            .collect()
+    } else {
+        vec![Accumulator::path_for(pngpath)]
+    }
+}
+
+fn get_stereo(m: &ArgMatches, camera: &Camera) -> Option<Stereo> {
+    let layout = m.value_of("stereo")?;
+    let convergence = if m.is_present("convergence") {
+        value_t!(m, "convergence", f64).unwrap()
+    } else {
+        camera.position.length_sq_to(&camera.look_at).sqrt()
+    };
+    if convergence <= 0. {
+        error!("the eyes can not converge at their own position");
+        process::exit(1);
+    }
+    /* the eyes converge by shifting their images, as only perspective
+     * projections do */
+    match camera.projection {
+        Projection::Legacy | Projection::Rectilinear => {}
+        p => {
+            error!("stereo needs a perspective projection, not {:?}", p);
+            process::exit(1);
+        }
+    }
+    let interocular = if m.is_present("interocular") {
+        value_t!(m, "interocular", f64).unwrap()
+    } else {
+        convergence / 30.
+    };
+    let stereo = Stereo {
+        layout: StereoLayout::parse(layout).unwrap(),
+        interocular,
+        convergence,
+    };
+    info!("stereo:{:?}", stereo);
+    Some(stereo)
+}
+
+/* Flag set once the render is interrupted */
+fn stop_on_signals() -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&stop)).ok();
+    signal_hook::flag::register(signal_hook::SIGTERM, Arc::clone(&stop)).ok();
+    stop
+}
+
+/* Rendering context, sampling and tiling given by the render arguments, on
+ * top of `scene_camera` */
+fn render_settings(
+    m: &ArgMatches,
+    scene: &Scene,
+    scene_camera: Option<&SceneCamera>,
+    ckpt_paths: &[PathBuf],
+) -> (RayCtx, SamplingSettings, TileSettings) {
+    let samples = value_t!(m, "samples", u64).unwrap();
+    let min_samples = if m.is_present("min_samples") {
+        value_t!(m, "min_samples", u64).unwrap()
+    } else {
+        samples
+    };
+    let noise_threshold = value_t!(m, "noise_threshold", f64).unwrap();
+    let pass_samples = value_t!(m, "pass_samples", u64).unwrap();
+    let sampler = SamplerKind::parse(m.value_of("sampler").unwrap()).unwrap();
+    if m.is_present("restart") {
+        for ckpt_path in ckpt_paths {
+            fs::remove_file(ckpt_path).ok();
+        }
+    }
+    let header = match ckpt_paths.iter().find(|p| p.exists()) {
+        Some(p) => match CheckpointHeader::read(p) {
+            Ok(header) => Some(header),
+            Err(why) => {
+                error!("{}", why);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let seed = get_seed(m, header.as_ref());
+    let (w, h) = match scene_camera {
+        Some(c) if m.occurrences_of("geometry") == 0 => (c.screen.width, c.screen.height),
+        _ => parse_geometry(m.value_of("geometry").unwrap()).unwrap(),
+    };
+    let tile_size = value_t!(m, "tile_size", u32).unwrap();
+    let region = if let Some(r) = m.value_of("region") {
+        parse_region(r).unwrap()
+    } else if let Some(p) = m.value_of("tiles") {
+        let (i, n) = parse_partition(p).unwrap();
+        match partition(w, h, tile_size, i, n) {
+            Ok(r) => r,
+            Err(why) => {
+                error!("{}", why);
+                process::exit(1);
+            }
+        }
+    } else {
+        Tile {
+            x: 0,
+            y: 0,
+            width: w,
+            height: h,
+        }
+    };
+    if !region.fits_in(w, h) {
+        error!("region {} is out of the image", region);
+        process::exit(1);
+    }
+    info!("region:{}", region);
+    let tiling = TileSettings {
+        size: tile_size,
+        order: TileOrder::parse(m.value_of("tile_order").unwrap()).unwrap(),
+        region,
+    };
+
+    let mut camera = get_camera(m, scene_camera.map(|c| &c.camera));
+    if m.is_present("auto_frame") {
+        match scene.bounds() {
+            Some(bounds) => {
+                camera.frame(&bounds, f64::from(w) / f64::from(h));
+                info!("framed camera:{:?}", camera);
+            }
+            None => warn!("nothing to frame in the scene"),
+        }
+    }
+    let preset = Preset {
+        camera,
+        nb_samples: samples,
+        screen: Screen {
+            width: w,
+            height: h,
+        },
+    };
+
+    let lambertian = !m.is_present("no_lambertian");
+    let shadows = !m.is_present("no_shadows");
+
+    let mut ray_ctx = RayCtx::new(&preset.camera, &preset.screen, lambertian, shadows);
+    if m.is_present("autofocus") && ray_ctx.autofocus(scene).is_none() {
+        warn!("nothing to focus on at the center of the image");
+    }
+
+    let sampling = SamplingSettings {
+        min_samples: min_samples.min(preset.nb_samples),
+        max_samples: preset.nb_samples,
+        noise_threshold,
+        pass_samples,
+        sampler,
+        seed,
+    };
+    (ray_ctx, sampling, tiling)
+}
+
 fn main() {
     pretty_env_logger::init();
     let m = App::new("Ray Tracer")
         .version("0.1.0")
         .author("Boris Faure <billiob@gmail.com>")
         .about("Generate ray traced images")
-        .subcommand(
+        .subcommand(camera_args(
             SubCommand::with_name("forest")
                 .about("construct a json file of a forest scene")
                 .arg(
@@ -144,6 +818,13 @@ fn main() {
                         .long("blue-sun")
                         .help("add a blue sun"),
                 )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .validator(is_seed)
+                        .help("seed of the random generators (default: random)"),
+                )
                 .arg(
                     Arg::with_name("geometry")
                         .short("g")
@@ -175,9 +856,16 @@ fn main() {
                         .default_value("(0.0, 1.0, -1.0)")
                         .validator(is_vec3)
                         .help("normal direction of the floor in the scene"),
+                )
+                .arg(
+                    Arg::with_name("sway")
+                        .long("sway")
+                        .takes_value(true)
+                        .validator(is_vec3)
+                        .help("how far the tops of the conifers move during a frame"),
                 ),
-        )
-        .subcommand(
+        ))
+        .subcommand(camera_args(
             SubCommand::with_name("extract")
                 .about("construct a json file of from a picture")
                 .arg(
@@ -241,64 +929,250 @@ fn main() {
                     Arg::with_name("blue_sun")
                         .long("blue-sun")
                         .help("add a blue sun"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .validator(is_seed)
+                        .help("seed of the random generators (default: random)"),
                 ),
+        ))
+        .subcommand(
+            render_args(SubCommand::with_name("render").about("renders a scene"))
+                .arg(
+                    Arg::with_name("all_cameras")
+                        .long("all-cameras")
+                        .conflicts_with("camera")
+                        .help("render every camera of the scene, to PNG with their name inserted"),
+                )
+                .arg(
+                    Arg::with_name("stereo")
+                        .long("stereo")
+                        .takes_value(true)
+                        .possible_values(&["side-by-side", "over-under", "anaglyph"])
+                        .help("render a left and a right view, and how to combine them"),
+                )
+                .arg(
+                    Arg::with_name("interocular")
+                        .long("interocular")
+                        .takes_value(true)
+                        .validator(is_length)
+                        .help("distance between the eyes (default: a 30th of --convergence)"),
+                )
+                .arg(
+                    Arg::with_name("convergence")
+                        .long("convergence")
+                        .takes_value(true)
+                        .validator(is_length)
+                        .help(
+                            "distance at which the eyes converge (default: distance to --look-at)",
+                        ),
+                ),
+        )
+        .subcommand(
+            render_args(
+                SubCommand::with_name("animate")
+                    .about("renders numbered frames of a camera going through keyframes"),
+            )
+            .arg(
+                Arg::with_name("keyframes")
+                    .long("keyframes")
+                    .takes_value(true)
+                    .required(true)
+                    .help("JSON array of {time, position, direction, fov} keyframes"),
+            )
+            .arg(
+                Arg::with_name("fps")
+                    .long("fps")
+                    .default_value("24")
+                    .validator(is_fps)
+                    .help("frames per second"),
+            )
+            .arg(
+                Arg::with_name("interpolation")
+                    .long("interpolation")
+                    .takes_value(true)
+                    .possible_values(&["linear", "catmull-rom"])
+                    .default_value("catmull-rom")
+                    .help("how the camera moves between keyframes"),
+            ),
+        )
+        .subcommand(
+            render_args(
+                SubCommand::with_name("turntable")
+                    .about("renders views from cameras orbiting around the scene"),
+            )
+            .arg(
+                Arg::with_name("views")
+                    .long("views")
+                    .default_value("8")
+                    .validator(is_nb_views)
+                    .help("number of views around the scene"),
+            )
+            .arg(
+                Arg::with_name("elevation")
+                    .long("elevation")
+                    .default_value("20")
+                    .validator(is_angle)
+                    .help("how far the cameras look down at the scene, in degrees"),
+            )
+            .arg(
+                Arg::with_name("distance")
+                    .long("distance")
+                    .takes_value(true)
+                    .validator(is_length)
+                    .help("distance to the center of the scene (default: to see all of it)"),
+            )
+            .arg(
+                Arg::with_name("contact_sheet")
+                    .long("contact-sheet")
+                    .help("gather the views into a grid in PNG, instead of only numbered frames"),
+            )
+            .arg(
+                Arg::with_name("columns")
+                    .long("columns")
+                    .takes_value(true)
+                    .validator(is_nb_views)
+                    .requires("contact_sheet")
+                    .help("number of views per row of the contact sheet (default: a square grid)"),
+            ),
         )
         .subcommand(
-            SubCommand::with_name("render")
-                .about("renders a scene")
+            render_args(
+                SubCommand::with_name("serve")
+                    .about("renders a scene with the help of workers connecting over TCP"),
+            )
+            .arg(
+                Arg::with_name("listen")
+                    .long("listen")
+                    .takes_value(true)
+                    .default_value("127.0.0.1:7878")
+                    .help("address to listen to workers on"),
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .default_value("600")
+                    .validator(is_timeout)
+                    .help("seconds after which a silent worker is given up on"),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("server")
+                .about("renders scenes submitted over HTTP")
                 .arg(
-                    Arg::with_name("CFG")
-                        .help("config file")
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8080")
+                        .help("address to serve the HTTP API on"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .takes_value(true)
+                        .default_value(".")
+                        .help("directory to render images to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("worker")
+                .about("renders tiles for a render coordinator")
+                .arg(
+                    Arg::with_name("ADDR")
+                        .help("address of the coordinator")
                         .required(true)
                         .index(1),
                 )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .default_value("600")
+                        .validator(is_timeout)
+                        .help("seconds after which a silent coordinator is given up on"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stack")
+                .about("combines renders of a scene made with different seeds")
                 .arg(
                     Arg::with_name("PNG")
-                        .help("png file to render to")
+                        .help("png file to write")
                         .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("RENDER")
+                        .help("checkpoints of the renders, or the png files next to them")
+                        .required(true)
+                        .multiple(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("adds the triangles of Wavefront OBJ models to a scene")
+                .arg(
+                    Arg::with_name("CFG")
+                        .help("config file, created if missing")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OBJ")
+                        .help("OBJ files to import")
+                        .required(true)
+                        .multiple(true)
                         .index(2),
                 )
                 .arg(
-                    Arg::with_name("samples")
-                        .short("s")
-                        .long("samples")
+                    Arg::with_name("group")
+                        .long("group")
                         .takes_value(true)
-                        .default_value("8")
-                        .help("number of rays to fire per pixel"),
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("only import this group of the models, can be repeated"),
                 )
                 .arg(
-                    Arg::with_name("eye_position")
-                        .short("e")
-                        .long("eye-position")
-                        .default_value("(0., 0., -25.)")
-                        .validator(is_vec3)
-                        .help("position of the eye in the scene"),
+                    Arg::with_name("scale")
+                        .long("scale")
+                        .default_value("1")
+                        .validator(is_length)
+                        .help("scale of the models"),
                 )
                 .arg(
-                    Arg::with_name("eye_direction")
-                        .short("i")
-                        .long("eye-direction")
-                        .default_value("(0., 0., 1.)")
+                    Arg::with_name("translate")
+                        .long("translate")
+                        .default_value("(0., 0., 0.)")
                         .validator(is_vec3)
-                        .help("direction of the eye in the scene"),
+                        .help("where the origin of the models goes in the scene"),
                 )
                 .arg(
-                    Arg::with_name("geometry")
-                        .short("g")
-                        .long("geometry")
-                        .default_value("4096x2160")
-                        .validator(is_geometry)
-                        .help("size of the image that would be generated"),
-                )
+                    Arg::with_name("crease_angle")
+                        .long("crease-angle")
+                        .takes_value(true)
+                        .validator(is_crease_angle)
+                        .help(
+                            "smooth the models, keeping edges sharper than this angle in degrees",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("assembles checkpoints of parts of a render")
                 .arg(
-                    Arg::with_name("no_shadows")
-                        .long("no-shadows")
-                        .help("Do not render shadows"),
+                    Arg::with_name("PNG")
+                        .help("png file to write")
+                        .required(true)
+                        .index(1),
                 )
                 .arg(
-                    Arg::with_name("no_lambertian")
-                        .long("no-lambertians")
-                        .help("Do not render lambertians"),
+                    Arg::with_name("CKPT")
+                        .help("checkpoints of the rendered parts")
+                        .required(true)
+                        .multiple(true)
+                        .index(2),
                 ),
         )
         .get_matches();
@@ -307,9 +1181,8 @@ fn main() {
         let cfgpath = m.value_of("CFG").unwrap();
         let density = value_t!(m, "density", f64).unwrap();
         let (w, h) = parse_geometry(m.value_of("geometry").unwrap()).unwrap();
-        let eye_pos = parse_vec3(m.value_of("eye_position").unwrap()).unwrap();
-        let eye_dir = parse_vec3(m.value_of("eye_direction").unwrap()).unwrap();
         let floor_dir = parse_vec3(m.value_of("floor").unwrap()).unwrap();
+        let seed = get_seed(m, None);
         let mut scene = Scene::new();
 
         if m.is_present("golden_sun") {
@@ -320,10 +1193,7 @@ fn main() {
             scene.set_blue_sun();
         }
         let preset = Preset {
-            eye: Eye {
-                origin: eye_pos,
-                direction: eye_dir,
-            },
+            camera: get_camera(m, None),
             nb_samples: 8_u64,
             screen: Screen {
                 width: w,
@@ -331,34 +1201,39 @@ fn main() {
             },
         };
 
-        let ray_ctx = RayCtx::new(&preset.eye, &preset.screen, false, false);
+        let ray_ctx = RayCtx::new(&preset.camera, &preset.screen, false, false);
 
         let floor = Plan::new(Vec3::origin(), floor_dir, Rgb([237, 201, 175]));
         let footprint = ray_ctx.get_footprint(&floor);
         info!("footprint:{:?}", footprint);
         scene.add(BaseObject::Plan(floor));
-        let trees = scene.generate_forest_monte_carlo(&footprint, density);
+        let trees = scene.generate_forest_monte_carlo(&footprint, density, seed);
         info!("trees:{:?}", trees);
+        if let Some(sway) = m.value_of("sway") {
+            let sway = parse_vec3(sway).unwrap();
+            for o in scene.objects.iter_mut() {
+                if let BaseObject::Conifer(c) = o {
+                    c.sway(&sway);
+                }
+            }
+        }
         scene.add_signature(&ray_ctx);
+        scene.add_camera("default", &ray_ctx);
 
         scene.save(Path::new(cfgpath));
     } else if let Some(m) = m.subcommand_matches("extract") {
         let pngpath = m.value_of("PNG").unwrap();
         let cfgpath = m.value_of("CFG").unwrap();
         let nb_vert_spheres = value_t!(m, "vertical_spheres", f64).unwrap();
-        let eye_pos = parse_vec3(m.value_of("eye_position").unwrap()).unwrap();
-        let eye_dir = parse_vec3(m.value_of("eye_direction").unwrap()).unwrap();
         let floor_dir = parse_vec3(m.value_of("floor").unwrap()).unwrap();
+        let seed = get_seed(m, None);
         let mut scene = Scene::new();
 
         let img = image::open(pngpath).unwrap();
         let buf = img.to_rgb();
 
         let preset = Preset {
-            eye: Eye {
-                origin: eye_pos,
-                direction: eye_dir,
-            },
+            camera: get_camera(m, None),
             nb_samples: 8_u64,
             screen: Screen {
                 width: buf.width(),
@@ -376,43 +1251,345 @@ fn main() {
             scene.set_blue_sun();
         }
 
-        let ray_ctx = RayCtx::new(&preset.eye, &preset.screen, false, false);
+        let ray_ctx = RayCtx::new(&preset.camera, &preset.screen, false, false);
         dbg!("rayctx:{:?}", ray_ctx);
 
         let floor = Plan::new(Vec3::origin(), floor_dir, Rgb([237, 201, 175]));
         scene.add(BaseObject::Plan(floor));
-        let spheres = scene.generate_from_image(&ray_ctx, buf, nb_vert_spheres);
+        let spheres = scene.generate_from_image(&ray_ctx, buf, nb_vert_spheres, seed);
         info!("spheres:{:?}", spheres);
         scene.add_signature(&ray_ctx);
+        scene.add_camera("default", &ray_ctx);
 
         scene.save(Path::new(cfgpath));
     } else if let Some(m) = m.subcommand_matches("render") {
         let pngpath = m.value_of("PNG").unwrap();
         let cfgpath = m.value_of("CFG").unwrap();
-        let samples = value_t!(m, "samples", u64).unwrap();
-        let (w, h) = parse_geometry(m.value_of("geometry").unwrap()).unwrap();
-        let eye_pos = parse_vec3(m.value_of("eye_position").unwrap()).unwrap();
-        let eye_dir = parse_vec3(m.value_of("eye_direction").unwrap()).unwrap();
+        let scene = Scene::load(Path::new(cfgpath));
 
+        /* every camera renders the same scene, loaded once */
+        let cameras: Vec<(Option<&SceneCamera>, String)> = if m.is_present("all_cameras") {
+            if scene.cameras.is_empty() {
+                error!("{} defines no camera", cfgpath);
+                process::exit(1);
+            }
+            scene
+                .cameras
+                .iter()
+                .map(|c| (Some(c), suffixed_path(pngpath, &c.name)))
+                .collect()
+        } else {
+            vec![(get_scene_camera(m, &scene), pngpath.to_owned())]
+        };
+
+        let stop = stop_on_signals();
+        for (scene_camera, pngpath) in cameras {
+            if let Some(c) = scene_camera {
+                info!("rendering camera {} to {}", c.name, pngpath);
+            }
+            let ckpt_paths = checkpoint_paths(m, &pngpath);
+            let (ray_ctx, sampling, tiling) = render_settings(m, &scene, scene_camera, &ckpt_paths);
+            let rendered = match get_stereo(m, &ray_ctx.camera) {
+                Some(stereo) => {
+                    stereo.render(&ray_ctx, &scene, &sampling, &tiling, &pngpath, &stop)
+                }
+                None => ray_ctx.render_scene_with(
+                    &scene,
+                    &sampling,
+                    &tiling,
+                    &pngpath,
+                    &stop,
+                    &print_progress,
+                ),
+            };
+            if let Err(why) = rendered {
+                error!("{}, use --restart to discard it", why);
+                process::exit(1);
+            }
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+    } else if let Some(m) = m.subcommand_matches("animate") {
+        let pngpath = m.value_of("PNG").unwrap();
+        let cfgpath = m.value_of("CFG").unwrap();
+        let keypath = m.value_of("keyframes").unwrap();
+        let interpolation = Interpolation::parse(m.value_of("interpolation").unwrap()).unwrap();
+        let animation = match Animation::load(Path::new(keypath), interpolation) {
+            Ok(a) => a,
+            Err(why) => {
+                error!("{}", why);
+                process::exit(1);
+            }
+        };
+        let fps = value_t!(m, "fps", f64).unwrap();
         let scene = Scene::load(Path::new(cfgpath));
 
-        let preset = Preset {
-            eye: Eye {
-                origin: eye_pos,
-                direction: eye_dir,
-            },
-            nb_samples: samples,
-            screen: Screen {
-                width: w,
-                height: h,
-            },
+        /* frames share the settings, and the seed, of the sequence */
+        let nb_frames = animation.nb_frames(fps);
+        let frames: Vec<String> = (1..=nb_frames).map(|n| frame_path(pngpath, n)).collect();
+        let ckpt_paths: Vec<PathBuf> = frames.iter().map(|p| Accumulator::path_for(p)).collect();
+        let scene_camera = get_scene_camera(m, &scene);
+        let (ray_ctx, sampling, tiling) = render_settings(m, &scene, scene_camera, &ckpt_paths);
+
+        let stop = stop_on_signals();
+        for (n, path) in frames.iter().enumerate() {
+            let time = animation.start() + n as f64 / fps;
+            let camera = animation.camera_at(&ray_ctx.camera, time);
+            let mut frame_ctx = RayCtx::new(
+                &camera,
+                &ray_ctx.screen,
+                ray_ctx.with_lambertian,
+                ray_ctx.with_shadows,
+            );
+            if m.is_present("autofocus") {
+                frame_ctx.autofocus(&scene);
+            }
+            info!("frame {}/{} at {}s", n + 1, nb_frames, time);
+            let rendered = frame_ctx.render_scene_with(
+                &scene,
+                &sampling,
+                &tiling,
+                path,
+                &stop,
+                &print_progress,
+            );
+            if let Err(why) = rendered {
+                error!("{}, use --restart to discard it", why);
+                process::exit(1);
+            }
+            println!();
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+    } else if let Some(m) = m.subcommand_matches("turntable") {
+        let pngpath = m.value_of("PNG").unwrap();
+        let cfgpath = m.value_of("CFG").unwrap();
+        let scene = Scene::load(Path::new(cfgpath));
+        let bounds = match scene.bounds() {
+            Some(b) => b,
+            None => {
+                error!("{} has nothing to turn around", cfgpath);
+                process::exit(1);
+            }
         };
+        let turntable = Turntable {
+            views: value_t!(m, "views", u32).unwrap(),
+            elevation: value_t!(m, "elevation", f64).unwrap(),
+            distance: value_t!(m, "distance", f64).unwrap_or(0.),
+        };
+        info!("turntable:{:?} around {:?}", turntable, bounds);
 
-        let lambertian = !m.is_present("no_lambertian");
-        let shadows = !m.is_present("no_shadows");
+        let frames: Vec<String> = (1..=turntable.views)
+            .map(|n| frame_path(pngpath, n))
+            .collect();
+        let ckpt_paths: Vec<PathBuf> = frames.iter().map(|p| Accumulator::path_for(p)).collect();
+        let scene_camera = get_scene_camera(m, &scene);
+        let (ray_ctx, sampling, tiling) = render_settings(m, &scene, scene_camera, &ckpt_paths);
+        let cameras = turntable.cameras(&ray_ctx.camera, &bounds, ray_ctx.aspect_ratio);
 
-        let ray_ctx = RayCtx::new(&preset.eye, &preset.screen, lambertian, shadows);
+        let stop = stop_on_signals();
+        for (n, (camera, path)) in cameras.iter().zip(&frames).enumerate() {
+            let mut view_ctx = RayCtx::new(
+                camera,
+                &ray_ctx.screen,
+                ray_ctx.with_lambertian,
+                ray_ctx.with_shadows,
+            );
+            if m.is_present("autofocus") {
+                view_ctx.autofocus(&scene);
+            }
+            info!("view {}/{}", n + 1, cameras.len());
+            let rendered = view_ctx.render_scene_with(
+                &scene,
+                &sampling,
+                &tiling,
+                path,
+                &stop,
+                &print_progress,
+            );
+            if let Err(why) = rendered {
+                error!("{}, use --restart to discard it", why);
+                process::exit(1);
+            }
+            println!();
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+        }
 
-        ray_ctx.render_scene(&scene, preset.nb_samples, pngpath);
+        if m.is_present("contact_sheet") {
+            let columns = value_t!(m, "columns", u32)
+                .unwrap_or_else(|_| f64::from(turntable.views).sqrt().ceil() as u32);
+            let views: Vec<_> = frames
+                .iter()
+                .map(|p| match image::open(p) {
+                    Ok(img) => img.to_rgba(),
+                    Err(why) => {
+                        error!("couldn't open {}: {}", p, why);
+                        process::exit(1);
+                    }
+                })
+                .collect();
+            contact_sheet(&views, columns).save(pngpath).ok();
+        }
+    } else if let Some(m) = m.subcommand_matches("serve") {
+        let pngpath = m.value_of("PNG").unwrap();
+        let cfgpath = m.value_of("CFG").unwrap();
+        let addr = m.value_of("listen").unwrap();
+        let scene_json = Scene::read_file(Path::new(cfgpath));
+        let scene = match Scene::from_json(&scene_json) {
+            Ok(s) => s,
+            Err(why) => {
+                error!("couldn't parse {}: {}", cfgpath, why);
+                process::exit(1);
+            }
+        };
+        let (ray_ctx, sampling, tiling) = render_settings(
+            m,
+            &scene,
+            get_scene_camera(m, &scene),
+            &[Accumulator::path_for(pngpath)],
+        );
+        let listener = match TcpListener::bind(addr) {
+            Ok(l) => l,
+            Err(why) => {
+                error!("couldn't listen on {}: {}", addr, why);
+                process::exit(1);
+            }
+        };
+        info!("waiting for workers on {}", addr);
+        let timeout = Duration::from_secs(value_t!(m, "timeout", u64).unwrap());
+        if let Err(why) = farm::serve(
+            listener,
+            &scene_json,
+            &ray_ctx,
+            &sampling,
+            &tiling,
+            pngpath,
+            timeout,
+        ) {
+            error!("{}, use --restart to discard it", why);
+            process::exit(1);
+        }
+    } else if let Some(m) = m.subcommand_matches("server") {
+        let addr = m.value_of("listen").unwrap();
+        let dir = m.value_of("dir").unwrap();
+        let listener = match TcpListener::bind(addr) {
+            Ok(l) => l,
+            Err(why) => {
+                error!("couldn't listen on {}: {}", addr, why);
+                process::exit(1);
+            }
+        };
+        info!("serving on http://{}/jobs", addr);
+        if let Err(why) = server::serve(listener, Path::new(dir)) {
+            error!("{}", why);
+            process::exit(1);
+        }
+    } else if let Some(m) = m.subcommand_matches("worker") {
+        let addr = m.value_of("ADDR").unwrap();
+        let timeout = Duration::from_secs(value_t!(m, "timeout", u64).unwrap());
+        if let Err(why) = farm::work(addr, timeout) {
+            error!("{}: {}", addr, why);
+            process::exit(1);
+        }
+    } else if let Some(m) = m.subcommand_matches("stack") {
+        let pngpath = m.value_of("PNG").unwrap();
+        let renders = m
+            .values_of("RENDER")
+            .unwrap()
+            .map(|p| {
+                let ckpt_path = if p.ends_with(".ckpt") {
+                    Path::new(p).to_path_buf()
+                } else {
+                    Accumulator::path_for(p)
+                };
+                match Accumulator::load(&ckpt_path) {
+                    Ok(render) => render,
+                    Err(why) => {
+                        error!("{}", why);
+                        process::exit(1);
+                    }
+                }
+            })
+            .collect();
+        match checkpoint::stack(renders) {
+            Ok((header, acc)) => {
+                acc.to_image(&header.screen).save(pngpath).ok();
+                acc.save(&header, &Accumulator::path_for(pngpath));
+            }
+            Err(why) => {
+                error!("{}", why);
+                process::exit(1);
+            }
+        }
+    } else if let Some(m) = m.subcommand_matches("import") {
+        let cfgpath = Path::new(m.value_of("CFG").unwrap());
+        let scale = value_t!(m, "scale", f64).unwrap();
+        let translation = parse_vec3(m.value_of("translate").unwrap()).unwrap();
+        let groups: Option<Vec<&str>> = m.values_of("group").map(|g| g.collect());
+        let crease_angle = value_t!(m, "crease_angle", f64).ok();
+        let mut scene = if cfgpath.exists() {
+            Scene::load(cfgpath)
+        } else {
+            Scene::new()
+        };
+        for objpath in m.values_of("OBJ").unwrap() {
+            let mut model = match ObjModel::load(Path::new(objpath)) {
+                Ok(model) => model,
+                Err(why) => {
+                    error!("{}", why);
+                    process::exit(1);
+                }
+            };
+            if let Some(groups) = &groups {
+                model.groups.retain(|g| groups.contains(&g.name.as_str()));
+            }
+            model.transform(scale, &translation);
+            let mut mesh = match model.mesh() {
+                Ok(mesh) => mesh,
+                Err(why) => {
+                    error!("{}: {}", objpath, why);
+                    process::exit(1);
+                }
+            };
+            if let Some(angle) = crease_angle {
+                mesh.smooth(angle);
+            }
+            info!(
+                "{}: {} groups, {} faces, {} triangles",
+                objpath,
+                model.groups.len(),
+                model.nb_faces(),
+                mesh.nb_triangles()
+            );
+            scene.add(BaseObject::Mesh(mesh));
+        }
+        scene.save(cfgpath);
+    } else if let Some(m) = m.subcommand_matches("merge") {
+        let pngpath = m.value_of("PNG").unwrap();
+        let parts = m
+            .values_of("CKPT")
+            .unwrap()
+            .map(|p| match Accumulator::load(Path::new(p)) {
+                Ok(part) => part,
+                Err(why) => {
+                    error!("{}", why);
+                    process::exit(1);
+                }
+            })
+            .collect();
+        match checkpoint::merge(parts) {
+            Ok((header, acc)) => {
+                acc.to_image(&header.screen).save(pngpath).ok();
+                acc.save(&header, &Accumulator::path_for(pngpath));
+            }
+            Err(why) => {
+                error!("{}", why);
+                process::exit(1);
+            }
+        }
     }
 }
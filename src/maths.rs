@@ -1,11 +1,11 @@
 use image::{Rgb, Rgba};
-use rand::Rng;
 use std::f64;
+use std::f64::consts::PI;
 use std::mem;
 
 pub const EPSILON: f64 = 0.000001;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Vec3 {
     pub x: f64,
     pub y: f64,
@@ -32,14 +32,12 @@ impl Vec3 {
         }
     }
 
-    pub fn random_in_unit_sphere() -> Vec3 {
-        let mut rng = rand::thread_rng();
-        let v = Vec3::new(
-            2_f64 * rng.gen::<f64>() - 1_f64,
-            2_f64 * rng.gen::<f64>() - 1_f64,
-            2_f64 * rng.gen::<f64>() - 1_f64,
-        );
-        v.normalize()
+    /* Map a point of [0,1)² onto the unit sphere, uniformly */
+    pub fn on_unit_sphere(u: f64, v: f64) -> Vec3 {
+        let z = 1_f64 - 2_f64 * u;
+        let r = (1_f64 - z * z).max(0_f64).sqrt();
+        let phi = 2_f64 * PI * v;
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
     }
     pub fn new_normalized(x: f64, y: f64, z: f64) -> Vec3 {
         let mut v: Vec3 = Vec3::new(x, y, z);
@@ -188,6 +186,11 @@ impl Vec3 {
         self.z = 1. / self.z;
     }
 
+    /* Relative luminance of a linear RGB color */
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
     pub fn mixed(&mut self, v: &Vec3, c: f64) {
         self.x = self.x * (1. - c) + v.x * c;
         self.y = self.y * (1. - c) + v.y * c;
@@ -256,127 +259,110 @@ impl Into<Vec3> for &Rgb<u8> {
     }
 }
 
-#[derive(Debug, Clone)]
-struct Row4 {
-    a: f64,
-    b: f64,
-    c: f64,
-    d: f64,
+/* {{{ Aabb */
+/* Axis-aligned bounding box */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
 }
-
-pub fn solve_3variable_system(a: &Vec3, b: &Vec3, c: &Vec3, p: &Vec3) -> Option<Vec3> {
-    let mut r1 = Row4 {
-        a: a.x,
-        b: b.x,
-        c: c.x,
-        d: p.x,
-    };
-    let mut r2 = Row4 {
-        a: a.y,
-        b: b.y,
-        c: c.y,
-        d: p.y,
-    };
-    let mut r3 = Row4 {
-        a: a.z,
-        b: b.z,
-        c: c.z,
-        d: p.z,
-    };
-
-    let mut shuffle = Vec3 {
-        x: 1.,
-        y: 2.,
-        z: 3.,
-    };
-    if r1.a == 0. {
-        if r2.a != 0. {
-            /* Swap Row1 and Row2 */
-            mem::swap(&mut r1, &mut r2);
-            mem::swap(&mut shuffle.x, &mut shuffle.y);
-        } else if r3.a != 0. {
-            /* Swap Row1 and Row3 */
-            mem::swap(&mut r1, &mut r3);
-            mem::swap(&mut shuffle.x, &mut shuffle.z);
-        } else {
-            return None;
-        }
-    }
-    if r2.b == 0. {
-        if r3.b != 0. {
-            /* Swap Row2 and Row3 */
-            mem::swap(&mut r2, &mut r3);
-            mem::swap(&mut shuffle.y, &mut shuffle.z);
-        } else {
-            return None;
+impl Aabb {
+    pub fn from_points(points: &[&Vec3]) -> Aabb {
+        let mut b = Aabb {
+            min: Vec3::infinity(),
+            max: Vec3::infinity().opposite(),
+        };
+        for p in points {
+            b.min = Vec3::new(b.min.x.min(p.x), b.min.y.min(p.y), b.min.z.min(p.z));
+            b.max = Vec3::new(b.max.x.max(p.x), b.max.y.max(p.y), b.max.z.max(p.z));
         }
+        b
     }
-    if r3.c == 0. {
-        return None;
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::from_points(&[&self.min, &self.max, &other.min, &other.max])
     }
-
-    // 1st step, put a 0 in r3.a using r1 and r3 rows
-    if r3.a != 0. {
-        let f1 = r1.a;
-        let f3 = r3.a;
-        r3.a = f1 * r3.a - f3 * r1.a;
-        r3.b = f1 * r3.b - f3 * r1.b;
-        r3.c = f1 * r3.c - f3 * r1.c;
-        r3.d = f1 * r3.d - f3 * r1.d;
-        assert!(r3.a == 0.);
+    pub fn translate(&self, v: &Vec3) -> Aabb {
+        Aabb {
+            min: self.min.addv(v),
+            max: self.max.addv(v),
+        }
     }
-
-    // 2nd step, put a 0 in r2.a using r1 and r2 rows
-    if r2.a != 0. {
-        let f1 = r1.a;
-        let f2 = r2.a;
-        r2.a = f1 * r2.a - f2 * r1.a;
-        r2.b = f1 * r2.b - f2 * r1.b;
-        r2.c = f1 * r2.c - f2 * r1.c;
-        r2.d = f1 * r2.d - f2 * r1.d;
-        assert!(r2.a == 0.);
+    /* The box, and where it is once moved by `velocity` */
+    pub fn sweep(&self, velocity: &Vec3) -> Aabb {
+        self.union(&self.translate(velocity))
+    }
+    pub fn center(&self) -> Vec3 {
+        self.min.avg(&self.max)
+    }
+    pub fn corners(&self) -> Vec<Vec3> {
+        let (a, b) = (&self.min, &self.max);
+        vec![
+            Vec3::new(a.x, a.y, a.z),
+            Vec3::new(b.x, a.y, a.z),
+            Vec3::new(a.x, b.y, a.z),
+            Vec3::new(b.x, b.y, a.z),
+            Vec3::new(a.x, a.y, b.z),
+            Vec3::new(b.x, a.y, b.z),
+            Vec3::new(a.x, b.y, b.z),
+            Vec3::new(b.x, b.y, b.z),
+        ]
+    }
+    /* Whether the ray from `origin`, whose direction has the inverse
+     * `inv_dir`, crosses the box between `tmin` and `tmax` */
+    pub fn hit(&self, origin: &Vec3, inv_dir: &Vec3, tmin: f64, tmax: f64) -> bool {
+        let (mut t0, mut t1) = (tmin, tmax);
+        for &(o, inv, lo, hi) in &[
+            (origin.x, inv_dir.x, self.min.x, self.max.x),
+            (origin.y, inv_dir.y, self.min.y, self.max.y),
+            (origin.z, inv_dir.z, self.min.z, self.max.z),
+        ] {
+            let (mut near, mut far) = ((lo - o) * inv, (hi - o) * inv);
+            if inv < 0. {
+                mem::swap(&mut near, &mut far);
+            }
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t1 < t0 {
+                return false;
+            }
+        }
+        true
     }
-
-    // 3rd step, put a 0 in r2.b using r2 and r3 rows
-    if r2.b != 0. {
-        let f2 = r2.b;
-        let f3 = r3.b;
-        r3.a = f2 * r3.a - f3 * r2.a;
-        r3.b = f2 * r3.b - f3 * r2.b;
-        r3.c = f2 * r3.c - f3 * r2.c;
-        r3.d = f2 * r3.d - f3 * r2.d;
-        assert!(r3.a == 0.);
+    /* Radius of the sphere around the box */
+    pub fn radius(&self) -> f64 {
+        self.min.length_sq_to(&self.max).sqrt() / 2.
     }
+}
+/* }}} */
 
-    if r3.c == 0. || r2.b == 0. || r1.a == 0. {
+/* Möller–Trumbore: where the line from `origin` along `direction` crosses the
+ * triangle (a, b, c), as the distance along `direction` and the weights of
+ * a, b and c at that point, whichever side it comes from */
+pub fn intersect_triangle(
+    origin: &Vec3,
+    direction: &Vec3,
+    [a, b, c]: [&Vec3; 3],
+) -> Option<(f64, Vec3)> {
+    let ab = a.to(b);
+    let ac = a.to(c);
+    let p = direction.cross_product(&ac);
+    let det = ab.dot_product(&p);
+    if det == 0. {
         return None;
     }
-
-    let mut r = Vec3::origin();
-    /* Compute the solution */
-    r.z = r3.d / r3.c;
-    r.y = (r2.d - r.z * r2.c) / r2.b;
-    r.x = (r1.d - r.z * r1.c - r.y * r1.b) / r1.a;
-
-    /* Shuffle back */
-    if shuffle.x != 1. {
-        if shuffle.y == 1. {
-            mem::swap(&mut r.x, &mut r.y);
-            mem::swap(&mut shuffle.x, &mut shuffle.y);
-        } else {
-            mem::swap(&mut r.x, &mut r.z);
-            mem::swap(&mut shuffle.x, &mut shuffle.z);
-        }
+    let inv_det = 1. / det;
+    let ao = a.to(origin);
+    let u = ao.dot_product(&p) * inv_det;
+    if !(0. ..=1.).contains(&u) {
+        return None;
     }
-    if shuffle.y != 2. {
-        mem::swap(&mut r.y, &mut r.z);
-        mem::swap(&mut shuffle.y, &mut shuffle.z);
+    let q = ao.cross_product(&ab);
+    let v = direction.dot_product(&q) * inv_det;
+    if v < 0. || u + v > 1. {
+        return None;
     }
-    assert!(shuffle.x == 1.);
-    assert!(shuffle.y == 2.);
-    assert!(shuffle.z == 3.);
-
-    return Some(r);
+    let t = ac.dot_product(&q) * inv_det;
+    Some((t, Vec3::new(1. - u - v, u, v)))
 }
 
 #[cfg(test)]
@@ -384,16 +370,18 @@ mod tests {
     use super::*;
 
     #[test]
-    fn solve_3variable_system_test() {
-        let a = Vec3::new(2., 5., -2.);
-        let b = Vec3::new(3., -1., -2.);
-        let c = Vec3::new(-4., 2., 3.);
-        let p = Vec3::new(-5., 15., 3.);
-        let r = solve_3variable_system(&a, &b, &c, &p);
-        assert!(r.is_some());
-        let r = r.unwrap();
-        assert_eq!(r.x, 2.);
-        assert_eq!(r.y, 1.);
-        assert_eq!(r.z, 3.);
+    fn lines_cross_triangles_through_the_origin() {
+        let a = Vec3::origin();
+        let b = Vec3::new(2., 0., 0.);
+        let c = Vec3::new(0., 2., 2.);
+        let origin = Vec3::new(0.5, 0.5, -3.);
+        let (t, w) = intersect_triangle(&origin, &Vec3::new(0., 0., 1.), [&a, &b, &c]).unwrap();
+        assert_eq!(t, 3.5);
+        assert_eq!(w, Vec3::new(0.5, 0.25, 0.25));
+        /* from behind, and missing it */
+        let (t, _) = intersect_triangle(&origin, &Vec3::new(0., 0., -1.), [&a, &b, &c]).unwrap();
+        assert_eq!(t, -3.5);
+        assert!(intersect_triangle(&origin, &Vec3::new(1., 0., 0.), [&a, &b, &c]).is_none());
+        assert!(intersect_triangle(&origin, &Vec3::new(2., 0., 1.), [&a, &b, &c]).is_none());
     }
 }
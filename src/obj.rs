@@ -0,0 +1,282 @@
+/* Wavefront OBJ import.
+ *
+ * Positions, normals and faces are read, polygons being split into
+ * triangles as fans; texture coordinates are ignored. Faces are gathered by
+ * group (`g` or `o`) and take the diffuse color (`Kd`) of their material,
+ * read from the `.mtl` files the model refers to. */
+
+use crate::maths::Vec3;
+use crate::object::Mesh;
+use image::Rgb;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+fn default_color() -> Rgb<u8> {
+    Rgb([200, 200, 200])
+}
+
+/* Indices, from 0, of what a corner of a face refers to */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjVertex {
+    pub position: usize,
+    pub normal: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjFace {
+    pub vertices: [ObjVertex; 3],
+    pub color: Rgb<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjGroup {
+    pub name: String,
+    pub faces: Vec<ObjFace>,
+}
+
+#[derive(Debug, Default)]
+pub struct ObjModel {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub groups: Vec<ObjGroup>,
+}
+
+fn parse_floats(fields: &[&str], n: usize) -> Result<Vec<f64>, String> {
+    if fields.len() < n {
+        return Err(format!("expecting {} numbers", n));
+    }
+    fields[..n]
+        .iter()
+        .map(|f| {
+            f.parse::<f64>()
+                .map_err(|_| format!("invalid number {}", f))
+        })
+        .collect()
+}
+
+/* Index of a 1-based, or negative hence relative, OBJ reference */
+fn parse_index(field: &str, len: usize) -> Result<usize, String> {
+    let i = field
+        .parse::<i64>()
+        .map_err(|_| format!("invalid index {}", field))?;
+    let idx = if i < 0 { len as i64 + i } else { i - 1 };
+    if idx < 0 || idx >= len as i64 {
+        return Err(format!("index {} out of range", field));
+    }
+    Ok(idx as usize)
+}
+
+/* Diffuse colors of the materials of a `.mtl` file */
+pub fn parse_mtl(text: &str) -> Result<HashMap<String, Rgb<u8>>, String> {
+    let mut colors = HashMap::new();
+    let mut current = None;
+    for (lineno, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let res = match fields.split_first() {
+            Some((&"newmtl", rest)) => {
+                current = Some(rest.join(" "));
+                Ok(())
+            }
+            Some((&"Kd", rest)) => parse_floats(rest, 3).map(|kd| {
+                if let Some(name) = &current {
+                    let c = |v: f64| (v.clamp(0., 1.) * 255.).round() as u8;
+                    colors.insert(name.clone(), Rgb([c(kd[0]), c(kd[1]), c(kd[2])]));
+                }
+            }),
+            _ => Ok(()),
+        };
+        res.map_err(|why| format!("line {}: {}", lineno + 1, why))?;
+    }
+    Ok(colors)
+}
+
+impl ObjModel {
+    pub fn load(path: &Path) -> Result<ObjModel, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|why| format!("couldn't open {}: {}", path.display(), why))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        ObjModel::parse(&text, dir).map_err(|why| format!("{}: {}", path.display(), why))
+    }
+
+    /* Parse an OBJ model, whose `.mtl` files are in `dir` */
+    pub fn parse(text: &str, dir: &Path) -> Result<ObjModel, String> {
+        let mut model = ObjModel::default();
+        let mut materials = HashMap::new();
+        let mut color = default_color();
+        let mut group = ObjGroup {
+            name: "default".to_owned(),
+            faces: Vec::new(),
+        };
+        for (lineno, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let res = match fields.split_first() {
+                Some((&"v", rest)) => parse_floats(rest, 3).map(|v| {
+                    model.positions.push(Vec3::new(v[0], v[1], v[2]));
+                }),
+                Some((&"vn", rest)) => parse_floats(rest, 3).map(|v| {
+                    model.normals.push(Vec3::new(v[0], v[1], v[2]));
+                }),
+                Some((&"f", rest)) => model.parse_face(rest, color).map(|faces| {
+                    group.faces.extend(faces);
+                }),
+                Some((&"g", rest)) | Some((&"o", rest)) => {
+                    let name = rest.join(" ");
+                    let previous = std::mem::replace(
+                        &mut group,
+                        ObjGroup {
+                            name,
+                            faces: Vec::new(),
+                        },
+                    );
+                    if !previous.faces.is_empty() {
+                        model.groups.push(previous);
+                    }
+                    Ok(())
+                }
+                Some((&"mtllib", rest)) => {
+                    let path = dir.join(rest.join(" "));
+                    fs::read_to_string(&path)
+                        .map_err(|why| format!("couldn't open {}: {}", path.display(), why))
+                        .and_then(|text| parse_mtl(&text))
+                        .map_err(|why| format!("{}: {}", path.display(), why))
+                        .map(|m| materials.extend(m))
+                }
+                Some((&"usemtl", rest)) => {
+                    let name = rest.join(" ");
+                    color = match materials.get(&name) {
+                        Some(c) => *c,
+                        None => {
+                            warn!("unknown material {}", name);
+                            default_color()
+                        }
+                    };
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            res.map_err(|why| format!("line {}: {}", lineno + 1, why))?;
+        }
+        if !group.faces.is_empty() {
+            model.groups.push(group);
+        }
+        Ok(model)
+    }
+
+    /* Triangles of a polygon whose corners are `v`, `v/vt`, `v//vn` or
+     * `v/vt/vn` */
+    fn parse_face(&self, fields: &[&str], color: Rgb<u8>) -> Result<Vec<ObjFace>, String> {
+        if fields.len() < 3 {
+            return Err("a face needs at least 3 vertices".to_owned());
+        }
+        let corners = fields
+            .iter()
+            .map(|f| {
+                let mut refs = f.split('/');
+                let position = parse_index(refs.next().unwrap(), self.positions.len())?;
+                /* texture coordinates */
+                refs.next();
+                let normal = match refs.next() {
+                    Some(r) if !r.is_empty() => Some(parse_index(r, self.normals.len())?),
+                    _ => None,
+                };
+                Ok(ObjVertex { position, normal })
+            })
+            .collect::<Result<Vec<ObjVertex>, String>>()?;
+        Ok((1..corners.len() - 1)
+            .map(|i| ObjFace {
+                vertices: [corners[0], corners[i], corners[i + 1]],
+                color,
+            })
+            .collect())
+    }
+
+    /* Scale the model by `scale`, then move it by `translation` */
+    pub fn transform(&mut self, scale: f64, translation: &Vec3) {
+        for p in &mut self.positions {
+            p.mult(scale);
+            *p = p.addv(translation);
+        }
+    }
+
+    pub fn nb_faces(&self) -> usize {
+        self.groups.iter().map(|g| g.faces.len()).sum()
+    }
+
+    /* Mesh of the faces, faces of the same color sharing a material. It is
+     * smooth when every corner has a normal, flat otherwise */
+    pub fn mesh(&self) -> Result<Mesh, String> {
+        let mut colors: Vec<Rgb<u8>> = Vec::new();
+        let mut faces = Vec::with_capacity(self.nb_faces());
+        let mut normal_faces = Vec::with_capacity(self.nb_faces());
+        let mut materials = Vec::with_capacity(self.nb_faces());
+        for face in self.groups.iter().flat_map(|g| &g.faces) {
+            let [a, b, c] = face.vertices;
+            faces.push([a.position as u32, b.position as u32, c.position as u32]);
+            if let (Some(a), Some(b), Some(c)) = (a.normal, b.normal, c.normal) {
+                normal_faces.push([a as u32, b as u32, c as u32]);
+            }
+            let material = match colors.iter().position(|c| *c == face.color) {
+                Some(m) => m,
+                None => {
+                    colors.push(face.color);
+                    colors.len() - 1
+                }
+            };
+            materials.push(material as u32);
+        }
+        let colors = colors.iter().map(|c| c.into()).collect();
+        let (normals, normal_faces) = if normal_faces.len() == faces.len() {
+            (self.normals.clone(), normal_faces)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        Mesh::new(
+            self.positions.clone(),
+            faces,
+            colors,
+            materials,
+            normals,
+            normal_faces,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polygons_are_triangulated_by_group() {
+        let text = "\
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vn 0 0 1
+# a quad with relative indices, then a triangle with every reference
+g quad
+f -4//1 -3//1 -2//1 -1//1
+o tri
+f 1/1/1 2/1/1 3/1/1
+";
+        let model = ObjModel::parse(text, Path::new("")).unwrap();
+        assert_eq!(model.groups.len(), 2);
+        assert_eq!(model.groups[0].name, "quad");
+        assert_eq!(model.groups[0].faces.len(), 2);
+        let v = model.groups[0].faces[1].vertices;
+        assert_eq!([v[0].position, v[1].position, v[2].position], [0, 2, 3]);
+        assert_eq!(v[0].normal, Some(0));
+        assert_eq!(model.groups[1].faces[0].vertices[2].normal, Some(0));
+        assert_eq!(model.mesh().unwrap().nb_triangles(), 3);
+
+        assert!(ObjModel::parse("v 0 0 0\nf 1 2 3\n", Path::new("")).is_err());
+        /* a null normal only makes its triangle flat */
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 0\nf 1//1 2//1 3//1\n";
+        let model = ObjModel::parse(text, Path::new("")).unwrap();
+        assert_eq!(model.mesh().unwrap().nb_triangles(), 1);
+        let colors = parse_mtl("newmtl bark\nKd 0.5 0.25 1\n").unwrap();
+        assert_eq!(colors["bark"], Rgb([128, 64, 255]));
+    }
+}
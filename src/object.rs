@@ -1,12 +1,16 @@
-use crate::maths::{solve_3variable_system, Vec3, EPSILON};
+use crate::maths::{intersect_triangle, Aabb, Vec3, EPSILON};
 use crate::raytracer::{Hit, Ray};
 use color_scaling::scale_rgb;
 use image::Rgb;
 use rand::Rng;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::f64::{self, consts::PI};
 
 pub trait ObjectTrait {
     fn hits(&self, r: &Ray, tmin: f64, tmax: f64) -> Option<Hit>;
+    /* Box holding the object during a frame, None when unbounded */
+    fn bounds(&self) -> Option<Aabb>;
 }
 
 /* {{{ Plan */
@@ -26,6 +30,9 @@ impl Plan {
     }
 }
 impl ObjectTrait for Plan {
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
     fn hits(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<Hit> {
         let dn = ray.direction.dot_product(&self.normal);
         if dn >= EPSILON {
@@ -42,6 +49,7 @@ impl ObjectTrait for Plan {
             normal: self.normal.clone(),
             p: p,
             t: t,
+            barycentric: None,
         };
         Some(h)
     }
@@ -49,6 +57,7 @@ impl ObjectTrait for Plan {
 
 /* }}} */
 /* {{{ Sphere */
+/* A sphere moves by `velocity` per frame, being at `center` at time 0 */
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Sphere {
     pub center: Vec3,
@@ -56,6 +65,8 @@ pub struct Sphere {
     pub rd_sq: f64,
     pub color: Vec3,
     pub shadows: bool,
+    #[serde(default = "Vec3::origin")]
+    pub velocity: Vec3,
 }
 impl Sphere {
     pub fn new(center: Vec3, radius: f64, color: Rgb<u8>, shadows: bool) -> Sphere {
@@ -65,16 +76,26 @@ impl Sphere {
             rd_sq: radius * radius,
             color: color.into(),
             shadows: shadows,
+            velocity: Vec3::origin(),
         }
     }
 }
 
 impl ObjectTrait for Sphere {
+    fn bounds(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        let b = Aabb {
+            min: self.center.subv(&r),
+            max: self.center.addv(&r),
+        };
+        Some(b.sweep(&self.velocity))
+    }
     fn hits(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<Hit> {
         if ray.is_light && !self.shadows {
             return None;
         }
-        let oc = self.center.to(&ray.origin);
+        let center = self.center.translate(&self.velocity, ray.time);
+        let oc = center.to(&ray.origin);
         let a = ray.direction.dot_product(&ray.direction);
         let b = oc.dot_product(&ray.direction);
         let c = oc.dot_product(&oc) - self.rd_sq;
@@ -86,26 +107,28 @@ impl ObjectTrait for Sphere {
         let t1 = (-b - discrimant_sqrt) / a;
         if tmin < t1 && t1 < tmax {
             let p = ray.at(t1);
-            let mut n = self.center.to(&p);
+            let mut n = center.to(&p);
             n.div(self.radius);
             let h = Hit {
                 color: self.color.clone(),
                 normal: n,
                 p: p,
                 t: t1,
+                barycentric: None,
             };
             return Some(h);
         }
         let t2 = (-b + discrimant_sqrt) / a;
         if tmin < t2 && t2 < tmax {
             let p = ray.at(t2);
-            let mut n = self.center.to(&p);
+            let mut n = center.to(&p);
             n.div(self.radius);
             let h = Hit {
                 color: self.color.clone(),
                 normal: n,
                 p: p,
                 t: t2,
+                barycentric: None,
             };
             return Some(h);
         }
@@ -140,11 +163,18 @@ impl Ellipsoid {
 }
 
 impl ObjectTrait for Ellipsoid {
+    fn bounds(&self) -> Option<Aabb> {
+        let b = self.sphere.bounds()?;
+        let min = b.min.multv(&self.radii).addv(&self.center);
+        let max = b.max.multv(&self.radii).addv(&self.center);
+        Some(Aabb::from_points(&[&min, &max]))
+    }
     fn hits(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<Hit> {
         let ray2 = Ray {
             origin: ray.origin.addv(&self.translation).multv(&self.inv_radii),
             direction: ray.direction.multv(&self.inv_radii),
             is_light: ray.is_light,
+            time: ray.time,
             ray_ctx: ray.ray_ctx,
         };
         let h = self.sphere.hits(&ray2, tmin, tmax);
@@ -156,6 +186,7 @@ impl ObjectTrait for Ellipsoid {
                 normal: n,
                 p: p,
                 t: hit.t,
+                barycentric: None,
             };
             Some(h)
         } else {
@@ -167,6 +198,7 @@ impl ObjectTrait for Ellipsoid {
 /* }}} */
 /* Triangle {{{ */
 
+/* Like spheres, triangles move by `velocity` per frame */
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Triangle {
     a: Vec3,
@@ -174,6 +206,8 @@ pub struct Triangle {
     c: Vec3,
     normal: Vec3,
     color: Vec3,
+    #[serde(default = "Vec3::origin")]
+    pub velocity: Vec3,
 }
 impl Triangle {
     pub fn new_ref(a: &Vec3, b: &Vec3, c: &Vec3, color: &Rgb<u8>) -> Triangle {
@@ -190,36 +224,54 @@ impl Triangle {
             c: c,
             normal: normal,
             color: color.into(),
+            velocity: Vec3::origin(),
         }
     }
 }
+/* Distance along `direction` from `origin` to the front face of the
+ * triangle (a, b, c), the side `normal` points to, if hit between `tmin`
+ * and `tmax`, and the weights of a, b and c at the hit point */
+fn front_hit(
+    vertices: [&Vec3; 3],
+    normal: &Vec3,
+    origin: &Vec3,
+    direction: &Vec3,
+    tmin: f64,
+    tmax: f64,
+) -> Option<(f64, Vec3)> {
+    if direction.dot_product(normal) >= 0. {
+        return None;
+    }
+    let (t, w) = intersect_triangle(origin, direction, vertices)?;
+    if t <= tmin || t >= tmax {
+        return None;
+    }
+    Some((t, w))
+}
+
 impl ObjectTrait for Triangle {
+    fn bounds(&self) -> Option<Aabb> {
+        let b = Aabb::from_points(&[&self.a, &self.b, &self.c]);
+        Some(b.sweep(&self.velocity))
+    }
     fn hits(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<Hit> {
-        /* find intersection with the plan */
-        let dn = ray.direction.dot_product(&self.normal);
-        if dn >= 0. {
-            return None;
-        }
-        let to_plan = ray.origin.to(&self.a);
-        let t = to_plan.dot_product(&self.normal) / dn;
-        if t <= tmin || t >= tmax {
-            return None;
-        }
-        let p = ray.at(t);
-        let o = solve_3variable_system(&self.a, &self.b, &self.c, &p);
-        if let Some(w) = o {
-            if w.x < 0. || w.y < 0. || w.z < 0. {
-                return None;
-            }
-            let h = Hit {
-                color: self.color.clone(),
-                normal: self.normal.clone(),
-                p: p,
-                t: t,
-            };
-            return Some(h);
-        }
-        return None;
+        /* move the ray rather than the triangle */
+        let origin = ray.origin.translate(&self.velocity, -ray.time);
+        let (t, w) = front_hit(
+            [&self.a, &self.b, &self.c],
+            &self.normal,
+            &origin,
+            &ray.direction,
+            tmin,
+            tmax,
+        )?;
+        Some(Hit {
+            color: self.color.clone(),
+            normal: self.normal.clone(),
+            p: ray.at(t),
+            t,
+            barycentric: Some(w),
+        })
     }
 }
 /* }}} */
@@ -259,8 +311,28 @@ impl Tetrahedron {
             color: color.into(),
         }
     }
+    pub fn top(&self) -> &Vec3 {
+        &self.side1.c
+    }
+    pub fn set_velocity(&mut self, velocity: &Vec3) {
+        for t in [
+            &mut self.base,
+            &mut self.side1,
+            &mut self.side2,
+            &mut self.side3,
+        ] {
+            t.velocity = velocity.clone();
+        }
+    }
 }
 impl ObjectTrait for Tetrahedron {
+    fn bounds(&self) -> Option<Aabb> {
+        let mut b = self.base.bounds()?;
+        for t in &[&self.side1, &self.side2, &self.side3] {
+            b = b.union(&t.bounds()?);
+        }
+        Some(b)
+    }
     fn hits(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<Hit> {
         let mut t_min = f64::INFINITY;
         let mut hit_min = None;
@@ -287,8 +359,7 @@ pub struct Conifer {
 }
 const CONIFER_RATIO: f64 = 1.8;
 impl Conifer {
-    pub fn new(base: Vec3, base_width: f64, steps: u8) -> Conifer {
-        let mut rng = rand::thread_rng();
+    pub fn new<R: Rng>(base: Vec3, base_width: f64, steps: u8, rng: &mut R) -> Conifer {
         let mut angle = rng.gen::<f64>() * 2. * PI;
         let mut tetrahedrons = Vec::new();
         let mut width = base_width;
@@ -331,7 +402,50 @@ impl Conifer {
         }
     }
 }
+impl Conifer {
+    /* Make the conifer sway, its top moving by `velocity` per frame. Each
+     * tetrahedron moves as a whole, by `velocity` scaled by the height of
+     * its top, so the lower ones move less but none stays still. Only
+     * holds for times within a frame */
+    pub fn sway(&mut self, velocity: &Vec3) {
+        let bottom = self
+            .tetrahedrons
+            .iter()
+            .map(|t| t.base.a.y.min(t.base.b.y).min(t.base.c.y))
+            .fold(f64::INFINITY, f64::min);
+        let top = self
+            .tetrahedrons
+            .iter()
+            .map(|t| t.top().y)
+            .fold(f64::NEG_INFINITY, f64::max);
+        for t in &mut self.tetrahedrons {
+            let mut v = velocity.clone();
+            v.mult((t.top().y - bottom) / (top - bottom));
+            t.set_velocity(&v);
+        }
+        /* keep the bounding sphere around the tetrahedrons, whose offsets
+         * are within |velocity| / 2 of half the one of the top */
+        let mut half = velocity.clone();
+        half.mult(0.5);
+        let margin = half.length_sq().sqrt();
+        let bs = &mut self.bounding_sphere;
+        bs.radius += margin;
+        bs.rd_sq = bs.radius * bs.radius;
+        bs.velocity = half;
+    }
+}
 impl ObjectTrait for Conifer {
+    fn bounds(&self) -> Option<Aabb> {
+        let mut bounds: Option<Aabb> = None;
+        for t in &self.tetrahedrons {
+            let b = t.bounds()?;
+            bounds = Some(match bounds {
+                Some(bounds) => bounds.union(&b),
+                None => b,
+            });
+        }
+        bounds
+    }
     fn hits(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<Hit> {
         let mut t_min = f64::INFINITY;
         let mut hit_min = None;
@@ -350,6 +464,370 @@ impl ObjectTrait for Conifer {
 }
 /* }}} */
 
+/* {{{ Mesh */
+
+/* Node of a bounding volume hierarchy. A leaf holds `count` triangles from
+ * `start` in the order of the mesh, an inner node has its first child right
+ * after it and its second one at `right` */
+#[derive(Clone)]
+struct BvhNode {
+    bounds: Aabb,
+    start: u32,
+    count: u32,
+    right: u32,
+}
+
+const BVH_LEAF_SIZE: usize = 4;
+
+/* How a mesh is stored: coordinates of the vertices, then three vertex
+ * indices per triangle. Triangles take the color of their material, the
+ * first one when there is no `materials`. Smooth meshes have normals, with
+ * three normal indices per triangle */
+#[derive(Serialize, Deserialize)]
+struct MeshData {
+    positions: Vec<f64>,
+    indices: Vec<u32>,
+    colors: Vec<Vec3>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    materials: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    normals: Vec<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    normal_indices: Vec<u32>,
+    #[serde(default = "Vec3::origin")]
+    velocity: Vec3,
+}
+
+/* Triangles sharing their vertices, culled from the back like `Triangle`.
+ * They are flat unless they have a normal at each corner, interpolated
+ * over the triangle. A bounding volume hierarchy is built whenever the mesh
+ * is loaded */
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "MeshData", into = "MeshData")]
+pub struct Mesh {
+    positions: Vec<Vec3>,
+    faces: Vec<[u32; 3]>,
+    colors: Vec<Vec3>,
+    materials: Vec<u32>,
+    pub velocity: Vec3,
+    face_normals: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    normal_faces: Vec<[u32; 3]>,
+    nodes: Vec<BvhNode>,
+    order: Vec<u32>,
+}
+
+impl TryFrom<MeshData> for Mesh {
+    type Error = String;
+    fn try_from(data: MeshData) -> Result<Mesh, String> {
+        if !data.positions.len().is_multiple_of(3)
+            || !data.normals.len().is_multiple_of(3)
+            || !data.indices.len().is_multiple_of(3)
+            || !data.normal_indices.len().is_multiple_of(3)
+        {
+            return Err("mesh buffers are not made of triples".to_owned());
+        }
+        let vectors = |b: &[f64]| b.chunks(3).map(|p| Vec3::new(p[0], p[1], p[2])).collect();
+        let triples = |b: &[u32]| b.chunks(3).map(|f| [f[0], f[1], f[2]]).collect();
+        let mut mesh = Mesh::new(
+            vectors(&data.positions),
+            triples(&data.indices),
+            data.colors,
+            data.materials,
+            vectors(&data.normals),
+            triples(&data.normal_indices),
+        )?;
+        mesh.velocity = data.velocity;
+        Ok(mesh)
+    }
+}
+
+impl From<Mesh> for MeshData {
+    fn from(mesh: Mesh) -> MeshData {
+        MeshData {
+            positions: mesh
+                .positions
+                .iter()
+                .flat_map(|p| vec![p.x, p.y, p.z])
+                .collect(),
+            indices: mesh.faces.iter().flat_map(|f| f.to_vec()).collect(),
+            colors: mesh.colors,
+            materials: mesh.materials,
+            normals: mesh
+                .normals
+                .iter()
+                .flat_map(|n| vec![n.x, n.y, n.z])
+                .collect(),
+            normal_indices: mesh.normal_faces.iter().flat_map(|f| f.to_vec()).collect(),
+            velocity: mesh.velocity,
+        }
+    }
+}
+
+impl Mesh {
+    /* Triangles `faces`, whose vertices are counterclockwise when seen from
+     * the front, colored by `colors[materials[face]]`. Smooth triangles have
+     * the normals of their corners in `normals`, at the indices given by
+     * `normal_faces`; none making the mesh flat, and a null one its
+     * triangle. Degenerate triangles are dropped */
+    pub fn new(
+        positions: Vec<Vec3>,
+        faces: Vec<[u32; 3]>,
+        colors: Vec<Vec3>,
+        materials: Vec<u32>,
+        normals: Vec<Vec3>,
+        normal_faces: Vec<[u32; 3]>,
+    ) -> Result<Mesh, String> {
+        if colors.is_empty() {
+            return Err("a mesh needs a color".to_owned());
+        }
+        if !materials.is_empty() && materials.len() != faces.len() {
+            return Err("a mesh needs a material per triangle".to_owned());
+        }
+        if faces
+            .iter()
+            .flatten()
+            .any(|&i| i as usize >= positions.len())
+        {
+            return Err("mesh vertex index out of range".to_owned());
+        }
+        if materials.iter().any(|&m| m as usize >= colors.len()) {
+            return Err("mesh material out of range".to_owned());
+        }
+        if !normal_faces.is_empty() && normal_faces.len() != faces.len() {
+            return Err("a smooth mesh needs normals for every triangle".to_owned());
+        }
+        if normal_faces
+            .iter()
+            .flatten()
+            .any(|&i| i as usize >= normals.len())
+        {
+            return Err("mesh normal index out of range".to_owned());
+        }
+        let mut mesh = Mesh {
+            positions,
+            faces: Vec::with_capacity(faces.len()),
+            colors,
+            materials: Vec::new(),
+            velocity: Vec3::origin(),
+            face_normals: Vec::with_capacity(faces.len()),
+            normals: normals
+                .iter()
+                .map(|n| {
+                    if n.length_sq() == 0. {
+                        n.clone()
+                    } else {
+                        n.normalize()
+                    }
+                })
+                .collect(),
+            normal_faces: Vec::with_capacity(normal_faces.len()),
+            nodes: Vec::new(),
+            order: Vec::new(),
+        };
+        for (i, f) in faces.iter().enumerate() {
+            let [a, b, c] = mesh.vertices(f);
+            let n = a.to(b).cross_product(&a.to(c));
+            if n.length_sq() == 0. {
+                continue;
+            }
+            let n = n.normalize();
+            if let Some(corners) = normal_faces.get(i) {
+                if corners
+                    .iter()
+                    .any(|&k| mesh.normals[k as usize].length_sq() == 0.)
+                {
+                    /* flat, with the normal of the triangle at every corner */
+                    mesh.normals.push(n.clone());
+                    let k = mesh.normals.len() as u32 - 1;
+                    mesh.normal_faces.push([k, k, k]);
+                } else {
+                    mesh.normal_faces.push(*corners);
+                }
+            }
+            mesh.face_normals.push(n);
+            mesh.faces.push(*f);
+            if !materials.is_empty() {
+                mesh.materials.push(materials[i]);
+            }
+        }
+        if mesh.faces.is_empty() {
+            return Err("a mesh needs triangles".to_owned());
+        }
+        let mut order: Vec<u32> = (0..mesh.faces.len() as u32).collect();
+        mesh.build(&mut order, 0);
+        mesh.order = order;
+        Ok(mesh)
+    }
+
+    pub fn nb_triangles(&self) -> usize {
+        self.faces.len()
+    }
+
+    /* Give each corner the average normal of the triangles around its
+     * vertex, weighted by their area, leaving out those making an angle of
+     * more than `crease_angle` degrees with its own triangle so that sharp
+     * edges stay sharp */
+    pub fn smooth(&mut self, crease_angle: f64) {
+        let mut around = vec![Vec::new(); self.positions.len()];
+        for (f, face) in self.faces.iter().enumerate() {
+            for &v in face {
+                around[v as usize].push(f);
+            }
+        }
+        let areas: Vec<Vec3> = self
+            .faces
+            .iter()
+            .map(|face| {
+                let [a, b, c] = self.vertices(face);
+                a.to(b).cross_product(&a.to(c))
+            })
+            .collect();
+        let cos_crease = crease_angle.to_radians().cos();
+        let mut normals = Vec::new();
+        let mut normal_faces = Vec::with_capacity(self.faces.len());
+        /* corners averaging the same triangles share their normal */
+        let mut shared = HashMap::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let mut corners = [0; 3];
+            for (corner, &v) in corners.iter_mut().zip(face) {
+                let own = &self.face_normals[f];
+                let smoothed: Vec<usize> = around[v as usize]
+                    .iter()
+                    .cloned()
+                    .filter(|&g| self.face_normals[g].dot_product(own) >= cos_crease - 1e-12)
+                    .collect();
+                *corner = *shared
+                    .entry((v, smoothed))
+                    .or_insert_with_key(|(_, faces)| {
+                        let n = faces.iter().fold(Vec3::origin(), |n, &g| n.addv(&areas[g]));
+                        normals.push(n.normalize());
+                        normals.len() as u32 - 1
+                    });
+            }
+            normal_faces.push(corners);
+        }
+        self.normals = normals;
+        self.normal_faces = normal_faces;
+    }
+
+    fn vertices(&self, f: &[u32; 3]) -> [&Vec3; 3] {
+        [
+            &self.positions[f[0] as usize],
+            &self.positions[f[1] as usize],
+            &self.positions[f[2] as usize],
+        ]
+    }
+    fn face_bounds(&self, f: u32) -> Aabb {
+        Aabb::from_points(&self.vertices(&self.faces[f as usize]))
+    }
+
+    /* Add the nodes for the triangles of `order`, which start at `start` in
+     * the order of the whole mesh. Returns the index of their root */
+    fn build(&mut self, order: &mut [u32], start: usize) -> usize {
+        let bounds = order
+            .iter()
+            .map(|&f| self.face_bounds(f))
+            .fold(self.face_bounds(order[0]), |a, b| a.union(&b));
+        let idx = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds,
+            start: start as u32,
+            count: order.len() as u32,
+            right: 0,
+        });
+        if order.len() <= BVH_LEAF_SIZE {
+            return idx;
+        }
+
+        /* split at the median along the axis where centers spread most */
+        let centers: Vec<Vec3> = order
+            .iter()
+            .map(|&f| self.face_bounds(f).center())
+            .collect();
+        let spread = Aabb::from_points(&centers.iter().collect::<Vec<_>>());
+        let extent = spread.min.to(&spread.max);
+        let axis = |v: &Vec3| {
+            if extent.x >= extent.y && extent.x >= extent.z {
+                v.x
+            } else if extent.y >= extent.z {
+                v.y
+            } else {
+                v.z
+            }
+        };
+        let mut keyed: Vec<(f64, u32)> = centers
+            .iter()
+            .map(axis)
+            .zip(order.iter().cloned())
+            .collect();
+        keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        for (o, k) in order.iter_mut().zip(&keyed) {
+            *o = k.1;
+        }
+        let mid = order.len() / 2;
+        let (left, right) = order.split_at_mut(mid);
+        self.nodes[idx].count = 0;
+        self.build(left, start);
+        let right = self.build(right, start + mid);
+        self.nodes[idx].right = right as u32;
+        idx
+    }
+}
+
+impl ObjectTrait for Mesh {
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.nodes[0].bounds.sweep(&self.velocity))
+    }
+    fn hits(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<Hit> {
+        /* move the ray rather than the mesh */
+        let origin = ray.origin.translate(&self.velocity, -ray.time);
+        let d = &ray.direction;
+        let inv_dir = Vec3::new(1. / d.x, 1. / d.y, 1. / d.z);
+        let mut closest = tmax;
+        let mut hit = None;
+        let mut stack = vec![0];
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            if !node.bounds.hit(&origin, &inv_dir, tmin, closest) {
+                continue;
+            }
+            if node.count == 0 {
+                stack.push(node.right as usize);
+                stack.push(n + 1);
+                continue;
+            }
+            let (start, end) = (node.start as usize, (node.start + node.count) as usize);
+            for &f in &self.order[start..end] {
+                let f = f as usize;
+                let vertices = self.vertices(&self.faces[f]);
+                let normal = &self.face_normals[f];
+                if let Some((t, w)) = front_hit(vertices, normal, &origin, d, tmin, closest) {
+                    closest = t;
+                    hit = Some((f, w));
+                }
+            }
+        }
+        let (f, w) = hit?;
+        let material = self.materials.get(f).cloned().unwrap_or(0) as usize;
+        let mut hit = Hit {
+            color: self.colors[material].clone(),
+            normal: self.face_normals[f].clone(),
+            p: ray.at(closest),
+            t: closest,
+            barycentric: Some(w),
+        };
+        if let Some(&[a, b, c]) = self.normal_faces.get(f) {
+            let n = |i: u32| &self.normals[i as usize];
+            if let Some(normal) = hit.interpolate([n(a), n(b), n(c)]) {
+                hit.normal = normal.normalize();
+            }
+        }
+        Some(hit)
+    }
+}
+/* }}} */
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum BaseObject {
     Plan(Plan),
@@ -358,8 +836,20 @@ pub enum BaseObject {
     Triangle(Triangle),
     Tetrahedron(Tetrahedron),
     Conifer(Conifer),
+    Mesh(Mesh),
 }
 impl ObjectTrait for BaseObject {
+    fn bounds(&self) -> Option<Aabb> {
+        match self {
+            BaseObject::Plan(p) => p.bounds(),
+            BaseObject::Sphere(s) => s.bounds(),
+            BaseObject::Ellipsoid(e) => e.bounds(),
+            BaseObject::Triangle(t) => t.bounds(),
+            BaseObject::Tetrahedron(t) => t.bounds(),
+            BaseObject::Conifer(c) => c.bounds(),
+            BaseObject::Mesh(m) => m.bounds(),
+        }
+    }
     fn hits(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<Hit> {
         match self {
             BaseObject::Plan(p) => p.hits(ray, tmin, tmax),
@@ -368,6 +858,146 @@ impl ObjectTrait for BaseObject {
             BaseObject::Triangle(t) => t.hits(ray, tmin, tmax),
             BaseObject::Tetrahedron(t) => t.hits(ray, tmin, tmax),
             BaseObject::Conifer(c) => c.hits(ray, tmin, tmax),
+            BaseObject::Mesh(m) => m.hits(ray, tmin, tmax),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::raytracer::{RayCtx, Screen};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn mesh_hits_the_closest_triangle() {
+        /* a bumpy grid facing the camera, with a material per row */
+        let n = 8;
+        let positions: Vec<Vec3> = (0..n * n)
+            .map(|k| {
+                let (x, y) = ((k % n) as f64, (k / n) as f64);
+                Vec3::new(x - 3.5, y - 3.5, 10. + ((x * 1.3).sin() * (y * 0.7).cos()))
+            })
+            .collect();
+        let mut faces = Vec::new();
+        let mut materials = Vec::new();
+        for y in 0..n - 1 {
+            for x in 0..n - 1 {
+                let k = y * n + x;
+                faces.push([k, k + n, k + 1]);
+                faces.push([k + 1, k + n, k + n + 1]);
+                materials.extend(&[y % 2, y % 2]);
+            }
+        }
+        let colors = vec![Vec3::new(1., 0., 0.), Vec3::new(0., 0., 1.)];
+        let mesh = Mesh::new(
+            positions.clone(),
+            faces.clone(),
+            colors,
+            materials,
+            Vec::new(),
+            Vec::new(),
+        )
+        .unwrap();
+        let triangles: Vec<Triangle> = faces
+            .iter()
+            .map(|f| {
+                let p = |i: u32| &positions[i as usize];
+                Triangle::new_ref(p(f[0]), p(f[2]), p(f[1]), &Rgb([0, 0, 0]))
+            })
+            .collect();
+        let json = serde_json::to_string(&BaseObject::Mesh(mesh)).unwrap();
+        let mesh: BaseObject = serde_json::from_str(&json).unwrap();
+
+        let camera = Camera::new(Vec3::origin(), &Vec3::new(0., 0., 1.));
+        let screen = Screen {
+            width: 4,
+            height: 4,
+        };
+        let ray_ctx = RayCtx::new(&camera, &screen, false, false);
+        let mut rng = SmallRng::seed_from_u64(48);
+        let mut nb_hits = 0;
+        for _ in 0..500 {
+            let ray = Ray {
+                origin: Vec3::new(rng.gen_range(-1., 1.), rng.gen_range(-1., 1.), 0.),
+                direction: Vec3::new(rng.gen_range(-0.5, 0.5), rng.gen_range(-0.5, 0.5), 1.)
+                    .normalize(),
+                is_light: false,
+                time: 0.,
+                ray_ctx: &ray_ctx,
+            };
+            /* every triangle, without the hierarchy */
+            let expected = triangles
+                .iter()
+                .filter_map(|t| t.hits(&ray, 0., f64::MAX))
+                .map(|h| h.t)
+                .fold(None, |m: Option<f64>, t| Some(m.map_or(t, |m| m.min(t))));
+            let hit = mesh.hits(&ray, 0., f64::MAX);
+            match (&hit, expected) {
+                (Some(h), Some(t)) => assert!((h.t - t).abs() < 1e-9),
+                (None, None) => (),
+                _ => panic!("mesh and triangles disagree"),
+            }
+            if let Some(h) = hit {
+                assert!(h.normal.z < 0.);
+                nb_hits += 1;
+            }
         }
+        assert!(nb_hits > 100);
+    }
+
+    #[test]
+    fn smooth_normals_are_interpolated_within_the_crease_angle() {
+        /* two triangles folded by 53 degrees along a ridge facing the camera */
+        let positions = vec![
+            Vec3::new(0.5, -1.5, 5.),
+            Vec3::new(0.5, 1.5, 5.),
+            Vec3::new(-1.5, 0.5, 6.),
+            Vec3::new(2.5, 0.5, 6.),
+        ];
+        let faces = vec![[0, 2, 1], [0, 1, 3]];
+        let colors = vec![Vec3::new(1., 1., 1.)];
+        let flat = Mesh::new(positions, faces, colors, Vec::new(), Vec::new(), Vec::new()).unwrap();
+        let camera = Camera::new(Vec3::origin(), &Vec3::new(0., 0., 1.));
+        let screen = Screen {
+            width: 4,
+            height: 4,
+        };
+        let ray_ctx = RayCtx::new(&camera, &screen, false, false);
+        /* close to the ridge, on the left triangle */
+        let ray = Ray {
+            origin: Vec3::origin(),
+            direction: Vec3::new(0.4, 0., 5.05).normalize(),
+            is_light: false,
+            time: 0.,
+            ray_ctx: &ray_ctx,
+        };
+        let face_normal = Vec3::new(-1., 0., -2.).normalize();
+        let normal_with = |crease_angle: f64| {
+            let mut mesh = flat.clone();
+            mesh.smooth(crease_angle);
+            let json = serde_json::to_string(&mesh).unwrap();
+            let mesh: Mesh = serde_json::from_str(&json).unwrap();
+            mesh.hits(&ray, 0., f64::MAX).unwrap().normal
+        };
+        let n = flat.hits(&ray, 0., f64::MAX).unwrap().normal;
+        assert!(n.to(&face_normal).length_sq() < 1e-18);
+        let n = normal_with(45.);
+        assert!(n.to(&face_normal).length_sq() < 1e-18);
+        let n = normal_with(60.);
+        assert!(n.x < 0. && n.x > -0.05);
+        assert!((n.length_sq() - 1.).abs() < 1e-12);
+
+        /* a degenerate triangle is dropped along with its normals */
+        let mut smooth = MeshData::from(flat.clone());
+        smooth.normals = vec![0., 0., -1.];
+        smooth.normal_indices = vec![0; 9];
+        smooth.indices.extend(&[0, 1, 1]);
+        let json = serde_json::to_string(&smooth).unwrap();
+        let mesh: Mesh = serde_json::from_str(&json).unwrap();
+        assert_eq!(mesh.nb_triangles(), 2);
+        assert_eq!(mesh.normal_faces.len(), 2);
     }
 }
@@ -1,15 +1,21 @@
+use crate::camera::{Camera, Projection};
+use crate::checkpoint::{self, Accumulator, CheckpointHeader, PixelAccumulator};
 use crate::maths::{Vec3, EPSILON};
 use crate::object::{ObjectTrait, Plan, Sphere};
+use crate::sampler::{Sampler, SamplerKind};
 use crate::scene::Scene;
+use crate::tile::{generate_tiles, TileSettings};
 use chrono::{DateTime, Local};
 use color_scaling::scale_rgb;
-use image::{Rgb, Rgba, RgbaImage};
-use rand::Rng;
+use image::Rgb;
 use rayon::prelude::*;
 use std::f64;
 use std::f64::consts::PI;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::Mutex;
 
 pub static DEPTH_MAX: u8 = 8;
 
@@ -19,6 +25,8 @@ pub struct Hit {
     pub normal: Vec3,
     pub p: Vec3,
     pub t: f64,
+    /* weights of the vertices at `p`, when a triangle is hit */
+    pub barycentric: Option<Vec3>,
 }
 
 impl Hit {
@@ -28,17 +36,56 @@ impl Hit {
             normal: Vec3::origin(),
             p: Vec3::origin(),
             t: f64::INFINITY,
+            barycentric: None,
         }
     }
+
+    /* Value at `p` of what is given at each vertex of the triangle hit */
+    pub fn interpolate(&self, values: [&Vec3; 3]) -> Option<Vec3> {
+        let w = self.barycentric.as_ref()?;
+        Some(
+            Vec3::origin()
+                .translate(values[0], w.x)
+                .translate(values[1], w.y)
+                .translate(values[2], w.z),
+        )
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Eye {
-    pub origin: Vec3,
-    pub direction: Vec3,
+/* Per pixel sample budget: pixels get at least `min_samples` samples, then
+ * more until their noise gets below `noise_threshold` or `max_samples` is
+ * reached.
+ * When `pass_samples` is not 0, the image is rendered progressively, each
+ * pass adding at most `pass_samples` samples to every pixel. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamplingSettings {
+    pub min_samples: u64,
+    pub max_samples: u64,
+    pub noise_threshold: f64,
+    pub pass_samples: u64,
+    pub sampler: SamplerKind,
+    pub seed: u64,
+}
+impl SamplingSettings {
+    pub fn is_pixel_done(&self, p: &PixelAccumulator) -> bool {
+        let n = u64::from(p.n);
+        n >= self.max_samples || (n >= self.min_samples && p.noise() <= self.noise_threshold)
+    }
 }
 
+/* Where a render stands, as reported after every tile */
 #[derive(Debug, Clone)]
+pub struct Progress {
+    pub rays: u64,
+    pub max_rays: u64,
+    pub percent: u64,
+    pub pass: u32,
+    pub nb_tiles_done: usize,
+    pub nb_tiles: usize,
+    pub end: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Screen {
     pub width: u32,
     pub height: u32,
@@ -46,12 +93,16 @@ pub struct Screen {
 #[derive(Debug)]
 pub struct RayCtx {
     pub aspect_ratio: f64,
-    pub eye: Eye,
+    pub camera: Camera,
     pub screen: Screen,
-    pub w: Vec3,
+    pub direction: Vec3,
     pub b: Vec3,
     pub v: Vec3,
     pub c: Vec3,
+    pub hfov: f64,
+    pub vfov: f64,
+    pub plane: (f64, f64),
+    pub ortho: (f64, f64),
     pub width: f64,
     pub height: f64,
     pub with_lambertian: bool,
@@ -62,6 +113,8 @@ pub struct Ray<'a> {
     pub origin: Vec3,
     pub direction: Vec3,
     pub is_light: bool,
+    /* when the ray is cast, as a fraction of a frame */
+    pub time: f64,
     pub ray_ctx: &'a RayCtx,
 }
 #[derive(Debug, Clone)]
@@ -87,27 +140,63 @@ impl Footprint {
     }
 }
 
+/* `pngpath` with `suffix` inserted before its extension */
+pub fn suffixed_path(pngpath: &str, suffix: &str) -> String {
+    let path = Path::new(pngpath);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, suffix),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/* Progress line of the renders made from the command line */
+pub fn print_progress(p: &Progress) {
+    print!(
+        "\r> {:>12} / {:} ({:3}%) pass {} tile {}/{} end at {:?}",
+        p.rays,
+        p.max_rays,
+        p.percent,
+        p.pass,
+        p.nb_tiles_done,
+        p.nb_tiles,
+        p.end.to_rfc2822(),
+    );
+    io::stdout().flush().ok();
+}
+
 impl RayCtx {
-    pub fn new(eye: &Eye, screen: &Screen, with_lambertian: bool, with_shadows: bool) -> RayCtx {
-        let w = Vec3::new_normalized(0., 1., 0.);
-        let b = w.cross_product(&eye.direction).normalize(); // →
-        let v = eye.direction.cross_product(&b).normalize(); // ↑
-        info!("w:{:?} b:{:?} v:{:?} eye:{:?}", w, b, v, eye,);
+    pub fn new(
+        camera: &Camera,
+        screen: &Screen,
+        with_lambertian: bool,
+        with_shadows: bool,
+    ) -> RayCtx {
+        let (b, v, direction) = camera.basis(); // →, ↑, ↗
+        info!("b:{:?} v:{:?} camera:{:?}", b, v, camera);
         let d = 1.;
-        let c = eye.origin.translate(&eye.direction, d);
+        let c = camera.position.translate(&direction, d);
         // Obtain the image's width and height.
         let width = screen.width as f64;
         let height = screen.height as f64;
         let aspect_ratio = width / height;
+        let (hfov, vfov) = camera.fov.angles(aspect_ratio);
+        let plane = camera.fov.plane(aspect_ratio);
+        let ortho = camera.ortho_extent(aspect_ratio);
 
         let r = RayCtx {
             aspect_ratio: aspect_ratio,
-            eye: (*eye).clone(),
+            camera: (*camera).clone(),
             screen: (*screen).clone(),
-            w: w,
+            direction,
             b: b,
             v: v,
             c: c,
+            hfov,
+            vfov,
+            plane,
+            ortho,
             width: width,
             height: height,
             with_lambertian: with_lambertian,
@@ -128,30 +217,94 @@ impl RayCtx {
         r
     }
 
+    /* Vector of coordinates (x, y, z) in the (→, ↑, ↗) camera basis */
+    fn camera_vec(&self, x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3::new(
+            x * self.b.x + y * self.v.x + z * self.direction.x,
+            x * self.b.y + y * self.v.y + z * self.direction.y,
+            x * self.b.z + y * self.v.z + z * self.direction.z,
+        )
+    }
+
+    /* Origin and direction of the ray seen at i, j */
+    pub fn primary_ray(&self, i: f64, j: f64) -> (Vec3, Vec3) {
+        let (x, y) = (i - 0.5, j - 0.5);
+        match self.camera.projection {
+            Projection::Legacy | Projection::Rectilinear => {
+                let screen_point = self.ij_to_screen(i, j);
+                let d = self.camera.position.to(&screen_point).normalize();
+                (self.camera.position.clone(), d)
+            }
+            Projection::Orthographic => {
+                let offset = self.camera_vec(2. * x * self.ortho.0, 2. * y * self.ortho.1, 0.);
+                (self.camera.position.addv(&offset), self.direction.clone())
+            }
+            Projection::Fisheye => {
+                /* the angle to the viewing direction grows linearly with
+                 * the distance to the center of the image */
+                let (dx, dy) = (x * self.hfov, y * self.vfov);
+                let theta = (dx * dx + dy * dy).sqrt();
+                let phi = dy.atan2(dx);
+                let d = self.camera_vec(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                (self.camera.position.clone(), d)
+            }
+            Projection::Equirectangular => {
+                let longitude = 2. * PI * x;
+                let latitude = PI * y;
+                let d = self.camera_vec(
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                    latitude.cos() * longitude.cos(),
+                );
+                (self.camera.position.clone(), d)
+            }
+        }
+    }
+
     pub fn ij_to_screen(&self, i: f64, j: f64) -> Vec3 {
+        match self.camera.projection {
+            Projection::Legacy => {}
+            Projection::Rectilinear => {
+                let x = 2. * (i - 0.5) * self.plane.0 + self.camera.shift.0;
+                let y = 2. * (j - 0.5) * self.plane.1 + self.camera.shift.1;
+                return self.c.addv(&self.camera_vec(x, y, 0.));
+            }
+            _ => {
+                let (o, d) = self.primary_ray(i, j);
+                return o.addv(&d);
+            }
+        }
+        /* the length of the direction the eye was given matters here */
+        let d = self.camera.position.to(&self.camera.look_at);
         let i = i - 0.5;
         let j = j - 0.5;
-        /* Horizontal fov is π/2 */
-        let vangle = PI / 2. * j / self.aspect_ratio;
+        let vangle = self.vfov * j;
         let vsin = vangle.sin();
         let vcos = vangle.cos();
-        let hangle = PI / 2. * i;
+        let hangle = self.hfov * i;
         let hsin = hangle.sin();
         let hcos = hangle.cos();
 
         let v = Vec3::new_normalized(
-            vsin * self.v.x + vcos * self.eye.direction.x,
-            vsin * self.v.y + vcos * self.eye.direction.y,
-            vsin * self.v.z + vcos * self.eye.direction.z,
+            vsin * self.v.x + vcos * d.x,
+            vsin * self.v.y + vcos * d.y,
+            vsin * self.v.z + vcos * d.z,
         );
         let h = Vec3::new_normalized(
-            hsin * self.b.x + hcos * self.eye.direction.x,
-            hsin * self.b.y + hcos * self.eye.direction.y,
-            hsin * self.b.z + hcos * self.eye.direction.z,
+            hsin * self.b.x + hcos * d.x,
+            hsin * self.b.y + hcos * d.y,
+            hsin * self.b.z + hcos * d.z,
         );
 
-        let dir = v.addv(&h).subv(&self.eye.direction);
-        self.eye.origin.addv(&dir)
+        let dir = v.addv(&h).subv(&d);
+        let depth = dir.dot_product(&self.direction);
+        let (sx, sy) = self.camera.shift;
+        let shift = self.camera_vec(sx * depth, sy * depth, 0.);
+        self.camera.position.addv(&dir).addv(&shift)
     }
 
     pub fn get_footprint(&self, floor: &Plan) -> Footprint {
@@ -172,131 +325,253 @@ impl RayCtx {
         }
     }
 
-    pub fn render_scene(&self, scene: &Scene, nsamples: u64, pngpath: &str) {
-        let mut buf: RgbaImage;
-        let undone_color = Rgba([255u8, 0u8, 255u8, 0u8]);
-        if let Ok(img) = image::open(pngpath) {
-            buf = img.as_rgba8().unwrap().clone();
-            assert!(buf.height() == self.screen.height);
-            assert!(buf.width() == self.screen.width);
+    /* Render the scene until every pixel is done or `stop` gets set,
+     * reporting progress after every tile */
+    pub fn render_scene_with(
+        &self,
+        scene: &Scene,
+        sampling: &SamplingSettings,
+        tiling: &TileSettings,
+        pngpath: &str,
+        stop: &AtomicBool,
+        on_progress: &(dyn Fn(&Progress) + Sync),
+    ) -> Result<(), String> {
+        let ckpt_path = Accumulator::path_for(pngpath);
+        let header = self.checkpoint_header(scene, sampling, tiling);
+        let acc = checkpoint::resume(&ckpt_path, &header)?;
+        let max_rays = tiling.region.nb_pixels() * sampling.max_samples;
+        let nb_rays = AtomicUsize::new(acc.nb_samples() as usize);
+        let acc = Mutex::new(acc);
+        let nb_pix_to_see = tiling.region.nb_pixels() as usize;
+
+        let tiles = generate_tiles(tiling);
+        let pass_samples = if sampling.pass_samples > 0 {
+            sampling.pass_samples
         } else {
-            buf = image::RgbaImage::new(self.screen.width as u32, self.screen.height as u32);
-            for (_, _, pixel) in buf.enumerate_pixels_mut() {
-                *pixel = undone_color;
+            sampling.max_samples
+        };
+
+        dbg!("rendering scene");
+        let mut pass = 0;
+        loop {
+            pass += 1;
+            let start: DateTime<Local> = Local::now();
+            let next_tile = AtomicUsize::new(0);
+            let nb_tiles_done = AtomicUsize::new(0);
+            let nb_pix = AtomicUsize::new(0);
+            let nb_pix_worked = AtomicUsize::new(0);
+
+            /* Workers pick tiles in order, so that rendering follows the
+             * requested tile order */
+            (0..rayon::current_num_threads())
+                .into_par_iter()
+                .for_each(|_| loop {
+                    if stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let idx = next_tile.fetch_add(1, Ordering::SeqCst);
+                    if idx >= tiles.len() {
+                        return;
+                    }
+                    let tile = &tiles[idx];
+
+                    let mut part = acc.lock().unwrap().extract(tile);
+                    let (worked, rays) = self.render_tile(scene, sampling, &mut part, pass_samples);
+                    nb_rays.fetch_add(rays as usize, Ordering::SeqCst);
+                    acc.lock().unwrap().paste(&part);
+
+                    let tile_pix = (tile.width * tile.height) as usize;
+                    let nb_pix = nb_pix.fetch_add(tile_pix, Ordering::SeqCst) + tile_pix;
+                    let pix_worked = nb_pix_worked.fetch_add(worked, Ordering::SeqCst) + worked;
+                    let nb_tiles_done = nb_tiles_done.fetch_add(1, Ordering::SeqCst) + 1;
+                    let skipped = nb_pix - pix_worked;
+
+                    let now: DateTime<Local> = Local::now();
+                    let duration = now.signed_duration_since(start);
+                    let d_ms = duration.num_milliseconds();
+                    let end_ms = if pix_worked > 0 {
+                        d_ms * ((nb_pix_to_see - skipped) as i64) / (pix_worked as i64)
+                    } else {
+                        d_ms * ((nb_pix_to_see - skipped) as i64)
+                    };
+                    let end_d = chrono::Duration::milliseconds(end_ms);
+                    let end = start.checked_add_signed(end_d).unwrap();
+
+                    on_progress(&Progress {
+                        rays: nb_rays.load(Ordering::SeqCst) as u64,
+                        max_rays,
+                        percent: 100_u64 * (nb_pix as u64) / (nb_pix_to_see as u64),
+                        pass,
+                        nb_tiles_done,
+                        nb_tiles: tiles.len(),
+                        end,
+                    });
+                });
+
+            let acc = acc.lock().unwrap();
+            /* renamed once written, so that it is never seen half written */
+            let tmp = suffixed_path(pngpath, "tmp");
+            if acc.to_image(&self.screen).save(&tmp).is_ok() {
+                fs::rename(&tmp, pngpath).ok();
+            }
+            acc.save(&header, &ckpt_path);
+            if stop.load(Ordering::SeqCst) || nb_pix_worked.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            if acc.pixels.iter().all(|p| sampling.is_pixel_done(p)) {
+                break;
             }
         }
-        let max_rays = (self.screen.width as u64) * (self.screen.height as u64) * nsamples;
-        let nb_pix = Arc::new(AtomicUsize::new(0));
-        let nb_pix_worked = Arc::new(AtomicUsize::new(0));
-        let nb_pix_to_see: usize = (self.screen.height * self.screen.width) as usize;
-        let start: DateTime<Local> = Local::now();
+        Ok(())
+    }
 
-        let stop = Arc::new(AtomicBool::new(false));
-        signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&stop)).ok();
-        signal_hook::flag::register(signal_hook::SIGTERM, Arc::clone(&stop)).ok();
+    pub fn checkpoint_header(
+        &self,
+        scene: &Scene,
+        sampling: &SamplingSettings,
+        tiling: &TileSettings,
+    ) -> CheckpointHeader {
+        CheckpointHeader {
+            scene_hash: scene.hash,
+            camera: self.camera.clone(),
+            screen: self.screen.clone(),
+            region: tiling.region.clone(),
+            max_samples: sampling.max_samples,
+            seed: sampling.seed,
+            sampler: sampling.sampler,
+            with_lambertian: self.with_lambertian,
+            with_shadows: self.with_shadows,
+            stacked: false,
+        }
+    }
 
-        dbg!("rendering scene");
-        buf.enumerate_pixels_mut()
-            .collect::<Vec<(u32, u32, &mut Rgba<u8>)>>()
+    /* Add at most `budget` samples to every pixel of `acc` that is not done
+     * yet. Returns the number of pixels worked on and of rays cast */
+    pub fn render_tile(
+        &self,
+        scene: &Scene,
+        sampling: &SamplingSettings,
+        acc: &mut Accumulator,
+        budget: u64,
+    ) -> (usize, u64) {
+        let region = acc.region.clone();
+        acc.pixels
             .par_iter_mut()
-            .for_each(|(x, y, pixel)| {
-                let stop = stop.load(Ordering::SeqCst);
-                if stop {
-                    return;
+            .enumerate()
+            .map(|(idx, pixel)| {
+                if sampling.is_pixel_done(pixel) {
+                    return (0, 0);
                 }
-                let worked;
-                if **pixel == undone_color {
-                    let i_min = *x as f64 / self.width;
-                    let i_max = (*x + 1) as f64 / self.width;
-                    let j_min = *y as f64 / self.height;
-                    let j_max = (*y + 1) as f64 / self.height;
-
-                    let i_step = i_max - i_min;
-                    let j_step = j_max - j_min;
-
-                    let mut r = 0_f64;
-                    let mut g = 0_f64;
-                    let mut b = 0_f64;
-
-                    let mut rng = rand::thread_rng();
-
-                    for _ in 0..nsamples {
-                        let i = i_min + rng.gen::<f64>() * i_step;
-                        let j = j_min + rng.gen::<f64>() * j_step;
-
-                        let p = self.cast_ray_from_eye(scene, i, 1_f64 - j);
-                        r += p.x;
-                        g += p.y;
-                        b += p.z;
-                    }
+                let x = region.x + (idx as u32) % region.width;
+                let y = region.y + (idx as u32) / region.width;
+                (1, self.render_pixel(scene, x, y, sampling, pixel, budget))
+            })
+            .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1))
+    }
 
-                    r /= nsamples as f64;
-                    g /= nsamples as f64;
-                    b /= nsamples as f64;
-                    **pixel = Vec3::new(r, g, b).into();
-                    worked = true;
-                } else {
-                    worked = false;
-                }
-                let pix_worked;
-                let nb_pix = nb_pix.fetch_add(1 as usize, Ordering::SeqCst);
-                if worked {
-                    pix_worked = nb_pix_worked.fetch_add(1 as usize, Ordering::SeqCst);
-                } else {
-                    pix_worked = nb_pix_worked.load(Ordering::SeqCst);
-                }
-                let skipped;
-                if pix_worked > 0 && nb_pix > pix_worked {
-                    skipped = nb_pix - pix_worked;
-                } else {
-                    skipped = 0_usize;
-                }
+    /* Add at most `budget` samples to pixel (x, y), stopping early once it
+     * is considered done. Returns the number of samples added */
+    fn render_pixel(
+        &self,
+        scene: &Scene,
+        x: u32,
+        y: u32,
+        sampling: &SamplingSettings,
+        acc: &mut PixelAccumulator,
+        budget: u64,
+    ) -> u64 {
+        let i_min = x as f64 / self.width;
+        let i_max = (x + 1) as f64 / self.width;
+        let j_min = y as f64 / self.height;
+        let j_max = (y + 1) as f64 / self.height;
 
-                let now: DateTime<Local> = Local::now();
-                let duration = now.signed_duration_since(start);
-                let d_ms = duration.num_milliseconds();
-                let end_ms;
-                if pix_worked > 0 {
-                    end_ms = d_ms * ((nb_pix_to_see - skipped) as i64) / (pix_worked as i64);
-                } else {
-                    end_ms = d_ms * ((nb_pix_to_see - skipped) as i64);
-                }
-                let end_d = chrono::Duration::milliseconds(end_ms);
-                let end = start.checked_add_signed(end_d).unwrap();
-
-                print!(
-                    "\r> {:>12} / {:} ({:3}%) end at {:?}",
-                    nb_pix * nsamples as usize,
-                    max_rays,
-                    100_u64 * (nb_pix as u64) / (nb_pix_to_see as u64),
-                    end.to_rfc2822(),
-                );
-            });
+        let i_step = i_max - i_min;
+        let j_step = j_max - j_min;
+
+        let min_samples = sampling.min_samples.max(1);
+        let mut sampler = sampling.sampler.create(min_samples, sampling.seed);
 
-        buf.save(pngpath).ok();
+        let mut n = 0;
+        while n < budget && !sampling.is_pixel_done(acc) {
+            /* samples keep on following the sequence of previous runs */
+            sampler.start_sample(x, y, u64::from(acc.n));
+            let (di, dj) = sampler.get_2d();
+            let i = i_min + di * i_step;
+            let j = j_min + dj * j_step;
+
+            let p = self.cast_ray_from_eye(scene, i, 1_f64 - j, &mut *sampler);
+            acc.push(&p);
+            n += 1;
+        }
+        n
     }
 
-    fn cast_ray_from_eye(&self, scene: &Scene, i: f64, j: f64) -> Vec3 {
-        let r = Ray::new(&self, i, j, false);
-        r.color(scene, 0)
+    fn cast_ray_from_eye(&self, scene: &Scene, i: f64, j: f64, sampler: &mut dyn Sampler) -> Vec3 {
+        let (lu, lv) = sampler.get_2d();
+        let time = if self.camera.has_motion_blur() {
+            self.camera.time(sampler.get_1d())
+        } else {
+            self.camera.shutter.0
+        };
+        let r = Ray::through_lens(self, i, j, lu, lv, time);
+        r.color(scene, 0, sampler)
+    }
+
+    /* Focus on whatever is at the center of the image, returning its
+     * distance */
+    pub fn autofocus(&mut self, scene: &Scene) -> Option<f64> {
+        let hit = {
+            let r = Ray::new(self, 0.5, 0.5, false);
+            let hit = r.hits(scene);
+            hit.t * r.direction.dot_product(&self.direction)
+        };
+        if hit.is_finite() && hit > 0. {
+            self.camera.focus_distance = hit;
+            info!("focus distance:{:?}", hit);
+            Some(hit)
+        } else {
+            None
+        }
     }
 }
 
 impl<'a> Ray<'a> {
     /* i, j in [0,1], in usual direction (origin is bottom left) */
     pub fn new(ctx: &'a RayCtx, i: f64, j: f64, is_light: bool) -> Ray<'a> {
-        /* Origin is the eye */
-        let screen_point = ctx.ij_to_screen(i, j);
-        let d = ctx.eye.origin.to(&screen_point).normalize();
+        let (origin, d) = ctx.primary_ray(i, j);
         let r = Ray {
-            origin: ctx.eye.origin.clone(),
+            origin,
             direction: d,
             is_light: is_light,
+            time: 0.,
             ray_ctx: ctx,
         };
         r
     }
 
+    /* Ray cast at `time`, starting from the point (lu, lv) of the lens and
+     * going through the point of the focal plane seen at i, j */
+    pub fn through_lens(ctx: &'a RayCtx, i: f64, j: f64, lu: f64, lv: f64, time: f64) -> Ray<'a> {
+        let mut r = Ray::new(ctx, i, j, false);
+        let cos = r.direction.dot_product(&ctx.direction);
+        if ctx.camera.aperture > 0.
+            && cos > EPSILON
+            && ctx.camera.projection != Projection::Orthographic
+        {
+            let focus = r.at(ctx.camera.focus_distance / cos);
+            let (x, y) = ctx.camera.lens_offset(lu, lv);
+            r.origin = Vec3::new(
+                r.origin.x + x * ctx.b.x + y * ctx.v.x,
+                r.origin.y + x * ctx.b.y + y * ctx.v.y,
+                r.origin.z + x * ctx.b.z + y * ctx.v.z,
+            );
+            r.direction = r.origin.to(&focus).normalize();
+        }
+        r.origin = r.origin.translate(&ctx.camera.velocity, time);
+        r.time = time;
+        r
+    }
+
     pub fn at(&self, t: f64) -> Vec3 {
         self.direction.at(&self.origin, t)
     }
@@ -314,7 +589,7 @@ impl<'a> Ray<'a> {
         hit_min
     }
 
-    fn color(&'a self, scene: &Scene, depth: u8) -> Vec3 {
+    fn color(&'a self, scene: &Scene, depth: u8, sampler: &mut dyn Sampler) -> Vec3 {
         let hit_min = self.hits(scene);
 
         if hit_min.t == f64::INFINITY {
@@ -337,19 +612,23 @@ impl<'a> Ray<'a> {
             scale_rgb(&c2, &c1, f64::abs(ud.y)).unwrap().into()
         } else {
             let mut c: Vec3;
+            let (bsdf_u, bsdf_v) = sampler.get_2d();
+            /* light dimensions, unused while the sun is a directional light */
+            sampler.get_2d();
             if self.ray_ctx.with_lambertian {
                 if depth > DEPTH_MAX {
                     return Vec3::new(0., 0., 0.);
                 }
-                let lambertian = |hit: &Hit, depth: u8| -> Vec3 {
-                    let u = Vec3::random_in_unit_sphere();
+                let mut lambertian = |hit: &Hit, depth: u8| -> Vec3 {
+                    let u = Vec3::on_unit_sphere(bsdf_u, bsdf_v);
                     let lambertian = Ray {
                         origin: hit.p.clone(),
                         direction: u.addv(&hit.normal),
                         is_light: false,
+                        time: self.time,
                         ray_ctx: self.ray_ctx,
                     };
-                    let c = lambertian.color(&scene, depth + 1);
+                    let c = lambertian.color(scene, depth + 1, sampler);
                     c.multv(&hit.color)
                 };
                 c = lambertian(&hit_min, depth);
@@ -363,6 +642,7 @@ impl<'a> Ray<'a> {
                         origin: start,
                         direction: sun.clone(),
                         is_light: true,
+                        time: self.time,
                         ray_ctx: self.ray_ctx,
                     };
                     let sun_hit = sun_ray.hits(scene);
@@ -377,3 +657,212 @@ impl<'a> Ray<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Fov;
+    use crate::object::BaseObject;
+    use crate::tile::{Tile, TileOrder};
+
+    fn render_with_threads(nb_threads: usize, name: &str) -> Vec<u8> {
+        let mut scene = Scene::new();
+        scene.add(BaseObject::Plan(Plan::new(
+            Vec3::origin(),
+            Vec3::new(0., 1., 0.),
+            Rgb([237, 201, 175]),
+        )));
+        scene.add(BaseObject::Sphere(Sphere::new(
+            Vec3::new(0., 1., 5.),
+            1.,
+            Rgb([0, 151, 0]),
+            true,
+        )));
+        scene.set_golden_sun();
+        let camera = Camera::new(Vec3::new(0., 1., 0.), &Vec3::new(0., 0., 1.));
+        let screen = Screen {
+            width: 12,
+            height: 8,
+        };
+        let ray_ctx = RayCtx::new(&camera, &screen, true, true);
+        let sampling = SamplingSettings {
+            min_samples: 2,
+            max_samples: 8,
+            noise_threshold: 0.05,
+            pass_samples: 0,
+            sampler: SamplerKind::Random,
+            seed: 42,
+        };
+        let path = std::env::temp_dir().join(format!("rt-test-{}.png", name));
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(Accumulator::path_for(path.to_str().unwrap())).ok();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(nb_threads)
+            .build()
+            .unwrap();
+        let tiling = TileSettings {
+            size: 5,
+            order: TileOrder::Spiral,
+            region: Tile {
+                x: 0,
+                y: 0,
+                width: 12,
+                height: 8,
+            },
+        };
+        pool.install(|| {
+            ray_ctx
+                .render_scene_with(
+                    &scene,
+                    &sampling,
+                    &tiling,
+                    path.to_str().unwrap(),
+                    &AtomicBool::new(false),
+                    &|_| {},
+                )
+                .unwrap()
+        });
+        let img = image::open(&path).unwrap().to_rgba().into_raw();
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(Accumulator::path_for(path.to_str().unwrap())).ok();
+        img
+    }
+
+    #[test]
+    fn rectilinear_keeps_lines_straight() {
+        let screen = Screen {
+            width: 16,
+            height: 9,
+        };
+        let mut camera = Camera::new(Vec3::origin(), &Vec3::new(1., 0., 1.));
+        camera.projection = Projection::Rectilinear;
+        let ray_ctx = RayCtx::new(&camera, &screen, false, false);
+        let a = ray_ctx.ij_to_screen(0., 0.1);
+        let b = ray_ctx.ij_to_screen(0.3, 0.1);
+        let c = ray_ctx.ij_to_screen(1., 0.1);
+        let n = a.to(&b).cross_product(&a.to(&c));
+        assert!(n.length_sq() < 1e-18);
+        /* the edges of the image are at half the fov from the center */
+        let edge = camera
+            .position
+            .to(&ray_ctx.ij_to_screen(1., 0.5))
+            .normalize();
+        let angle = edge.dot_product(&camera.direction()).acos();
+        assert!((angle - PI / 4.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn legacy_projection_keeps_the_original_mapping() {
+        /* the eye of `rt forest`, its direction not being of unit length */
+        let camera = Camera::new(Vec3::new(0., 25., 0.), &Vec3::new(0., -1., 1.));
+        let screen = Screen {
+            width: 16,
+            height: 9,
+        };
+        let ray_ctx = RayCtx::new(&camera, &screen, false, false);
+        let p = ray_ctx.ij_to_screen(0.2, 0.7);
+        let expected = Vec3::new(-0.3389600714259311, 24.721802695961376, 0.455367746633458);
+        assert!(p.length_sq_to(&expected) < 1e-18, "{:?}", p);
+    }
+
+    #[test]
+    fn wide_projections_map_the_image_center_forward() {
+        let screen = Screen {
+            width: 32,
+            height: 16,
+        };
+        let mut camera = Camera::new(Vec3::new(1., 2., 3.), &Vec3::new(1., 0., 1.));
+        camera.fov = Fov::Horizontal(360.);
+        let forward = camera.direction();
+        for projection in &[
+            Projection::Orthographic,
+            Projection::Fisheye,
+            Projection::Equirectangular,
+        ] {
+            camera.projection = *projection;
+            let ray_ctx = RayCtx::new(&camera, &screen, false, false);
+            let (origin, d) = ray_ctx.primary_ray(0.5, 0.5);
+            assert!(origin.length_sq_to(&camera.position) < 1e-18);
+            assert!(d.to(&forward).length_sq() < 1e-18);
+        }
+        /* both sides of a panorama, or of a 360° fisheye, look backward */
+        for projection in &[Projection::Fisheye, Projection::Equirectangular] {
+            camera.projection = *projection;
+            let ray_ctx = RayCtx::new(&camera, &screen, false, false);
+            let (_, d) = ray_ctx.primary_ray(0., 0.5);
+            assert!((d.dot_product(&forward) + 1.).abs() < 1e-9);
+        }
+        /* an orthographic camera casts parallel rays */
+        camera.projection = Projection::Orthographic;
+        camera.ortho_width = 4.;
+        let ray_ctx = RayCtx::new(&camera, &screen, false, false);
+        let (o0, d0) = ray_ctx.primary_ray(0., 0.5);
+        let (o1, d1) = ray_ctx.primary_ray(1., 0.5);
+        assert!(d0.to(&d1).length_sq() < 1e-18);
+        assert!((o0.length_sq_to(&o1) - 16.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn moving_sphere_is_hit_at_ray_time() {
+        let screen = Screen {
+            width: 1,
+            height: 1,
+        };
+        let camera = Camera::new(Vec3::origin(), &Vec3::new(0., 0., 1.));
+        let ray_ctx = RayCtx::new(&camera, &screen, false, false);
+        let mut sphere = Sphere::new(Vec3::new(0., 0., 5.), 1., Rgb([255, 0, 0]), true);
+        sphere.velocity = Vec3::new(3., 0., 0.);
+        let mut ray = Ray::new(&ray_ctx, 0.5, 0.5, false);
+        assert!(sphere.hits(&ray, 0., f64::INFINITY).is_some());
+        ray.time = 1.;
+        assert!(sphere.hits(&ray, 0., f64::INFINITY).is_none());
+        ray.origin = Vec3::new(3., 0., 0.);
+        let hit = sphere.hits(&ray, 0., f64::INFINITY).unwrap();
+        assert!((hit.t - 4.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn noisy_pixels_get_more_samples() {
+        /* a lambertian floor under the sky */
+        let mut scene = Scene::new();
+        scene.add(BaseObject::Plan(Plan::new(
+            Vec3::origin(),
+            Vec3::new(0., 1., 0.),
+            Rgb([237, 201, 175]),
+        )));
+        scene.set_golden_sun();
+        let camera = Camera::new(Vec3::new(0., 1., 0.), &Vec3::new(0., 0., 1.));
+        let screen = Screen {
+            width: 64,
+            height: 64,
+        };
+        let ray_ctx = RayCtx::new(&camera, &screen, true, true);
+        let sampling = SamplingSettings {
+            min_samples: 4,
+            max_samples: 64,
+            noise_threshold: 0.01,
+            pass_samples: 0,
+            sampler: SamplerKind::Random,
+            seed: 42,
+        };
+        let nb_samples = |x, y| {
+            let mut acc = Accumulator::new(&Tile {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            });
+            ray_ctx.render_tile(&scene, &sampling, &mut acc, u64::MAX);
+            acc.get(x, y).n
+        };
+        /* the sky barely changes within a pixel, the floor is lit by
+         * random bounces */
+        assert_eq!(nb_samples(0, 0), 4);
+        assert_eq!(nb_samples(32, 60), 64);
+    }
+
+    #[test]
+    fn seeded_render_does_not_depend_on_threads() {
+        assert!(render_with_threads(1, "seed-1") == render_with_threads(3, "seed-3"));
+    }
+}
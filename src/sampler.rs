@@ -0,0 +1,373 @@
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+/*
+ * Samples are drawn dimension after dimension, always in the same order so
+ * that low-discrepancy samplers can give each dimension its own sequence:
+ *   - pixel position (2D)
+ *   - lens position (2D)
+ *   - time (1D), only when the shutter stays open
+ *   - then for every bounce: BSDF direction (2D) and light (2D)
+ */
+
+/* Hashing helpers {{{ */
+
+/* splitmix64 finalizer */
+pub fn mix64(z: u64) -> u64 {
+    let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+pub fn hash3(a: u64, b: u64, c: u64) -> u64 {
+    mix64(a ^ mix64(b ^ mix64(c)))
+}
+
+fn to_unit_u32(v: u32) -> f64 {
+    (v as f64) / 4_294_967_296_f64
+}
+
+fn to_unit_u64(v: u64) -> f64 {
+    ((v >> 11) as f64) / 9_007_199_254_740_992_f64
+}
+
+/* Kensler's hash-based permutation of [0, l) */
+fn permute(i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    let mut i = i;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+/* }}} */
+
+pub trait Sampler {
+    /* Start sample number `index` of pixel (x, y) */
+    fn start_sample(&mut self, x: u32, y: u32, index: u64);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SamplerKind {
+    Random,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn parse(val: &str) -> Result<SamplerKind, String> {
+        match val {
+            "random" => Ok(SamplerKind::Random),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err("invalid sampler".to_owned()),
+        }
+    }
+
+    /* `nsamples` is the number of samples expected per pixel */
+    pub fn create(self, nsamples: u64, seed: u64) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Random => Box::new(RandomSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(nsamples, seed)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+/* {{{ Random */
+/* Independent samples, from a stream seeded per pixel and per sample */
+pub struct RandomSampler {
+    seed: u64,
+    rng: SmallRng,
+}
+impl RandomSampler {
+    pub fn new(seed: u64) -> RandomSampler {
+        RandomSampler {
+            seed,
+            rng: SmallRng::seed_from_u64(seed),
+        }
+    }
+}
+impl Sampler for RandomSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u64) {
+        let pixel = (u64::from(y) << 32) | u64::from(x);
+        self.rng = SmallRng::seed_from_u64(hash3(self.seed, pixel, index));
+    }
+    fn get_1d(&mut self) -> f64 {
+        self.rng.gen::<f64>()
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.rng.gen::<f64>(), self.rng.gen::<f64>())
+    }
+}
+/* }}} */
+/* {{{ Stratified */
+/* Each dimension is stratified over a round of `nsamples` samples, strata
+ * being visited in a different order per pixel and per dimension. */
+pub struct StratifiedSampler {
+    seed: u64,
+    nx: u32,
+    ny: u32,
+    n1: u32,
+    pixel: u64,
+    index: u64,
+    dim: u64,
+}
+impl StratifiedSampler {
+    pub fn new(nsamples: u64, seed: u64) -> StratifiedSampler {
+        let n = nsamples.clamp(1, 1 << 24) as u32;
+        let nx = (n as f64).sqrt().ceil() as u32;
+        let ny = n.div_ceil(nx);
+        StratifiedSampler {
+            seed,
+            nx,
+            ny,
+            n1: n,
+            pixel: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+    /* `n` is the size of a round of samples in the current dimension */
+    fn next_hash(&mut self, n: u32) -> u64 {
+        let round = self.index / u64::from(n);
+        let h = hash3(self.seed, self.pixel, (self.dim << 32) | round);
+        self.dim += 1;
+        h
+    }
+}
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u64) {
+        self.pixel = (u64::from(y) << 32) | u64::from(x);
+        self.index = index;
+        self.dim = 0;
+    }
+    fn get_1d(&mut self) -> f64 {
+        let h = self.next_hash(self.n1);
+        let s = (self.index % u64::from(self.n1)) as u32;
+        let stratum = permute(s, self.n1, h as u32);
+        let jitter = to_unit_u64(mix64(h ^ self.index));
+        (f64::from(stratum) + jitter) / f64::from(self.n1)
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        let n = self.nx * self.ny;
+        let h = self.next_hash(n);
+        let s = (self.index % u64::from(n)) as u32;
+        let stratum = permute(s, n, h as u32);
+        let jx = to_unit_u64(mix64(h ^ self.index));
+        let jy = to_unit_u64(mix64(h ^ !self.index));
+        (
+            (f64::from(stratum % self.nx) + jx) / f64::from(self.nx),
+            (f64::from(stratum / self.nx) + jy) / f64::from(self.ny),
+        )
+    }
+}
+/* }}} */
+/* {{{ Halton */
+const PRIMES: [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+fn radical_inverse(base: u64, index: u64) -> f64 {
+    let inv_base = 1. / base as f64;
+    let mut inv = inv_base;
+    let mut i = index;
+    let mut r = 0_f64;
+    while i > 0 {
+        r += (i % base) as f64 * inv;
+        i /= base;
+        inv *= inv_base;
+    }
+    r
+}
+
+/* Halton sequence, decorrelated between pixels with a per pixel and per
+ * dimension Cranley-Patterson rotation */
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    index: u64,
+    dim: u64,
+}
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler {
+            seed,
+            pixel: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+}
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u64) {
+        self.pixel = (u64::from(y) << 32) | u64::from(x);
+        self.index = index;
+        self.dim = 0;
+    }
+    fn get_1d(&mut self) -> f64 {
+        let base = PRIMES[(self.dim % PRIMES.len() as u64) as usize];
+        let offset = to_unit_u64(hash3(self.seed, self.pixel, self.dim));
+        self.dim += 1;
+        let v = radical_inverse(base, self.index) + offset;
+        v - v.floor()
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        let a = self.get_1d();
+        let b = self.get_1d();
+        (a, b)
+    }
+}
+/* }}} */
+/* {{{ Sobol */
+/* Owen-scrambled Sobol' points, using the hash based scrambling from
+ * Burley's "Practical Hash-based Owen Scrambling". Each 2D draw uses the
+ * first two Sobol' dimensions with its own index shuffling. */
+fn sobol_directions_1() -> [u32; 32] {
+    let mut dirs = [0_u32; 32];
+    let mut v = 1_u32 << 31;
+    for d in dirs.iter_mut() {
+        *d = v;
+        v ^= v >> 1;
+    }
+    dirs
+}
+
+fn sobol(index: u32, directions: &[u32; 32]) -> u32 {
+    let mut x = 0_u32;
+    let mut i = index;
+    let mut bit = 0;
+    while i != 0 {
+        if i & 1 == 1 {
+            x ^= directions[bit];
+        }
+        i >>= 1;
+        bit += 1;
+    }
+    x
+}
+
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    let mut x = x;
+    x ^= x.wrapping_mul(0x3d20_adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x0552_6c56);
+    x ^= x.wrapping_mul(0x53a2_2864);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+pub struct SobolSampler {
+    seed: u64,
+    directions: [u32; 32],
+    pixel: u64,
+    index: u32,
+    dim: u64,
+}
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler {
+            seed,
+            directions: sobol_directions_1(),
+            pixel: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+    fn next_seed(&mut self) -> u64 {
+        let h = hash3(self.seed, self.pixel, self.dim);
+        self.dim += 1;
+        h
+    }
+}
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u64) {
+        self.pixel = (u64::from(y) << 32) | u64::from(x);
+        self.index = index as u32;
+        self.dim = 0;
+    }
+    fn get_1d(&mut self) -> f64 {
+        let h = self.next_seed();
+        let i = nested_uniform_scramble(self.index, h as u32);
+        to_unit_u32(nested_uniform_scramble(i.reverse_bits(), (h >> 32) as u32))
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        let h = self.next_seed();
+        let i = nested_uniform_scramble(self.index, h as u32);
+        let x = i.reverse_bits();
+        let y = sobol(i, &self.directions);
+        let h2 = mix64(h);
+        (
+            to_unit_u32(nested_uniform_scramble(x, (h >> 32) as u32)),
+            to_unit_u32(nested_uniform_scramble(y, h2 as u32)),
+        )
+    }
+}
+/* }}} */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stratified_covers_every_stratum() {
+        let mut s = StratifiedSampler::new(16, 42);
+        let mut seen = [false; 16];
+        for idx in 0..16 {
+            s.start_sample(3, 7, idx);
+            let (u, v) = s.get_2d();
+            assert!((0. ..1.).contains(&u) && (0. ..1.).contains(&v));
+            let cell = (v * 4.) as usize * 4 + (u * 4.) as usize;
+            assert!(!seen[cell]);
+            seen[cell] = true;
+        }
+    }
+
+    #[test]
+    fn sobol_is_stratified_in_2d() {
+        let mut s = SobolSampler::new(7);
+        let mut seen = [false; 16];
+        for idx in 0..16 {
+            s.start_sample(1, 2, idx);
+            let (u, v) = s.get_2d();
+            let cell = (v * 4.) as usize * 4 + (u * 4.) as usize;
+            assert!(!seen[cell]);
+            seen[cell] = true;
+        }
+    }
+}
@@ -1,19 +1,46 @@
-use crate::maths::Vec3;
+use crate::camera::Camera;
+use crate::maths::{Aabb, Vec3};
 use crate::object::{BaseObject, Conifer, ObjectTrait, Sphere};
-use crate::raytracer::{Footprint, Ray, RayCtx};
+use crate::raytracer::{Footprint, Ray, RayCtx, Screen};
+use crate::sampler::hash3;
 use image::{Rgb, RgbImage};
-use rand::Rng;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use std::error::Error;
 use std::f64::{self, consts::PI};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 use std::path::Path;
 
+/* A viewpoint of the scene, with the size of its image */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SceneCamera {
+    pub name: String,
+    pub camera: Camera,
+    pub screen: Screen,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Scene {
     pub objects: Vec<BaseObject>,
     pub sun: Option<(Vec3, Vec3, f64)>,
+    /* the first one is rendered by default */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cameras: Vec<SceneCamera>,
+    /* hash of the file the scene was loaded from */
+    #[serde(skip)]
+    pub hash: u64,
+}
+
+/* 64-bit FNV-1a */
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut h = 0xcbf2_9ce4_8422_2325_u64;
+    for b in bytes {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
 }
 
 /* {{{ Circle */
@@ -50,6 +77,8 @@ impl Scene {
         Scene {
             objects: Vec::new(),
             sun: None,
+            cameras: Vec::new(),
+            hash: 0,
         }
     }
     pub fn add(&mut self, obj: BaseObject) {
@@ -72,22 +101,67 @@ impl Scene {
             0.9,
         )));
     }
+    /* Record the camera of `ray_ctx` as `name`, replacing any camera of
+     * that name */
+    pub fn add_camera(&mut self, name: &str, ray_ctx: &RayCtx) {
+        self.cameras.retain(|c| c.name != name);
+        self.cameras.push(SceneCamera {
+            name: name.to_owned(),
+            camera: ray_ctx.camera.clone(),
+            screen: ray_ctx.screen.clone(),
+        });
+    }
+    /* The camera called `name`, or the default one */
+    pub fn camera(&self, name: Option<&str>) -> Option<&SceneCamera> {
+        match name {
+            Some(name) => self.cameras.iter().find(|c| c.name == name),
+            None => self.cameras.first(),
+        }
+    }
+    /* Box around the bounded objects of the scene, None when there is
+     * none */
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.objects
+            .iter()
+            .filter_map(|o| o.bounds())
+            .fold(None, |acc, b| match acc {
+                Some(acc) => Some(b.union(&acc)),
+                None => Some(b),
+            })
+    }
     pub fn load(json_file_path: &Path) -> Scene {
-        let f = match File::open(&json_file_path) {
+        let bytes = Scene::read_file(json_file_path);
+        match Scene::from_json(&bytes) {
             Err(why) => {
                 let display = json_file_path.display();
-                panic!("couldn't open {}: {}", display, why.description())
+                panic!("couldn't open {}: {}", display, why)
             }
-            Ok(file) => file,
-        };
-        let reader = BufReader::new(f);
-        match serde_json::from_reader(reader) {
+            Ok(s) => s,
+        }
+    }
+    pub fn read_file(json_file_path: &Path) -> Vec<u8> {
+        let f = match File::open(&json_file_path) {
             Err(why) => {
                 let display = json_file_path.display();
                 panic!("couldn't open {}: {}", display, why.description())
             }
-            Ok(s) => s,
+            Ok(file) => file,
+        };
+        let mut reader = BufReader::new(f);
+        let mut bytes = Vec::new();
+        if let Err(why) = reader.read_to_end(&mut bytes) {
+            let display = json_file_path.display();
+            panic!("couldn't read {}: {}", display, why)
         }
+        bytes
+    }
+    pub fn from_json(bytes: &[u8]) -> Result<Scene, String> {
+        let mut scene: Scene = match serde_json::from_slice(bytes) {
+            Err(why) => return Err(why.to_string()),
+            Ok(s) => s,
+        };
+        scene.hash = fnv1a(bytes);
+        Ok(scene)
     }
     pub fn save(&self, json_file_path: &Path) {
         let f = match File::create(&json_file_path) {
@@ -108,6 +182,7 @@ impl Scene {
         ray_ctx: &RayCtx,
         buf: RgbImage,
         nb_vert_spheres: f64,
+        seed: u64,
     ) -> usize {
         let black = Rgb([0, 0, 0]);
         let mut nb_spheres: usize = 0;
@@ -212,36 +287,22 @@ impl Scene {
             if w > 0 {
                 Rgb([(r / w) as u8, (g / w) as u8, (b / w) as u8])
             } else {
-                let mut rng = rand::thread_rng();
+                let mut rng = SmallRng::seed_from_u64(hash3(seed, i.to_bits(), j.to_bits()));
                 Rgb([rng.gen::<u8>(), rng.gen::<u8>(), rng.gen::<u8>()])
             }
         };
         let add_point = |i: f64, j: f64, is_front: bool| {
-            /* Vertical fov is π/2 */
-            let vangle = PI / 2. * (j - 0.5) / ray_ctx.aspect_ratio;
-            let vsin = vangle.sin();
-            let vcos = vangle.cos();
-            let hangle = PI / 2. * (i - 0.5);
-            let hsin = hangle.sin();
-            let hcos = hangle.cos();
-
-            let v = Vec3::new_normalized(
-                vsin * ray_ctx.v.x + vcos * ray_ctx.eye.direction.x,
-                vsin * ray_ctx.v.y + vcos * ray_ctx.eye.direction.y,
-                vsin * ray_ctx.v.z + vcos * ray_ctx.eye.direction.z,
-            );
-            let h = Vec3::new_normalized(
-                hsin * ray_ctx.b.x + hcos * ray_ctx.eye.direction.x,
-                hsin * ray_ctx.b.y + hcos * ray_ctx.eye.direction.y,
-                hsin * ray_ctx.b.z + hcos * ray_ctx.eye.direction.z,
-            );
-            let dir = v.addv(&h).subv(&ray_ctx.eye.direction).normalize();
+            let dir = ray_ctx
+                .camera
+                .position
+                .to(&ray_ctx.ij_to_screen(i, j))
+                .normalize();
             let (f, r) = if is_front {
                 (f, radius)
             } else {
                 (f + radius * 1.1, radius * 1.1)
             };
-            let c = dir.at(&ray_ctx.eye.origin, f);
+            let c = dir.at(&ray_ctx.camera.position, f);
             let s = Sphere::new(c.clone(), r, black.clone(), true);
             let color = get_color(&s, i, j);
             Sphere::new(c, r, color.clone(), true)
@@ -277,9 +338,9 @@ impl Scene {
         let diameter = 0.008 * p_bottom_right.length_sq_to(&p_top_right).sqrt();
         let radius = diameter / 2.;
         let c = ray_ctx
-            .eye
-            .origin
-            .translate(&ray_ctx.eye.direction, 1. + 2. * diameter);
+            .camera
+            .position
+            .translate(&ray_ctx.direction, 1. + 2. * diameter);
         let bottom_right = Vec3::new(
             c.x + ray_ctx.b.x - ray_ctx.v.x / ray_ctx.aspect_ratio,
             c.y + ray_ctx.b.y - ray_ctx.v.y / ray_ctx.aspect_ratio,
@@ -369,8 +430,13 @@ impl Scene {
         add_point(24., 4.);
     }
 
-    pub fn generate_forest_monte_carlo(&mut self, footprint: &Footprint, threshold: f64) -> u32 {
-        let mut rng = rand::thread_rng();
+    pub fn generate_forest_monte_carlo(
+        &mut self,
+        footprint: &Footprint,
+        threshold: f64,
+        seed: u64,
+    ) -> u32 {
+        let mut rng = SmallRng::seed_from_u64(seed);
         let mut width = 1.5_f64;
         let mut r = width / 4_f64;
         let surface_max = footprint.get_surface() * threshold;
@@ -397,7 +463,7 @@ impl Scene {
                 }
             } else {
                 tries = 0;
-                let conifer = Conifer::new(pos, this_width, 5_u8);
+                let conifer = Conifer::new(pos, this_width, 5_u8, &mut rng);
                 self.add(BaseObject::Conifer(conifer));
                 vec.push(c);
                 trees += 1;
@@ -410,3 +476,34 @@ impl Scene {
         trees
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cameras_are_found_by_name() {
+        let screen = Screen {
+            width: 16,
+            height: 9,
+        };
+        let ray_ctx = |x| {
+            let camera = Camera::new(Vec3::new(x, 1.1, -7.3), &Vec3::new(0.1, 0., 1.));
+            RayCtx::new(&camera, &screen, true, true)
+        };
+        let mut scene = Scene::new();
+        assert!(scene.camera(None).is_none());
+        scene.add_camera("front", &ray_ctx(0.));
+        scene.add_camera("side", &ray_ctx(1. / 3.));
+        scene.add_camera("front", &ray_ctx(2.));
+        let json = serde_json::to_vec(&scene).unwrap();
+        let scene = Scene::from_json(&json).unwrap();
+
+        assert_eq!(scene.cameras.len(), 2);
+        assert_eq!(scene.camera(None).unwrap().name, "side");
+        let side = scene.camera(Some("side")).unwrap();
+        assert_eq!(side.camera, ray_ctx(1. / 3.).camera);
+        assert_eq!(scene.camera(Some("front")).unwrap().camera.position.x, 2.);
+        assert!(scene.camera(Some("back")).is_none());
+    }
+}
@@ -0,0 +1,609 @@
+/* Long running render service with a small HTTP API:
+ *
+ *   POST   /jobs            submit a job, answers its id
+ *   GET    /jobs            status of every job
+ *   GET    /jobs/ID         status and progress of a job
+ *   DELETE /jobs/ID         cancel a job
+ *   GET    /jobs/ID/image   latest image rendered for a job
+ *
+ * Jobs are rendered one at a time, in the order they were submitted, each
+ * render using every thread. Images and checkpoints go to `dir`. */
+
+use crate::camera::Camera;
+use crate::checkpoint::Accumulator;
+use crate::maths::Vec3;
+use crate::raytracer::{Progress, RayCtx, SamplingSettings, Screen};
+use crate::sampler::SamplerKind;
+use crate::scene::Scene;
+use crate::tile::{Tile, TileOrder, TileSettings};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/* {{{ Jobs */
+
+fn default_camera() -> Camera {
+    Camera::new(Vec3::new(0., 0., -25.), &Vec3::new(0., 0., 1.))
+}
+fn default_screen() -> Screen {
+    Screen {
+        width: 4096,
+        height: 2160,
+    }
+}
+fn default_samples() -> u64 {
+    8
+}
+fn default_noise_threshold() -> f64 {
+    0.01
+}
+fn default_sampler() -> SamplerKind {
+    SamplerKind::Sobol
+}
+fn default_tile_size() -> u32 {
+    32
+}
+fn default_tile_order() -> String {
+    "spiral".to_owned()
+}
+
+/* Largest image rendered, its accumulator taking 44 bytes per pixel */
+const MAX_PIXELS: u64 = 1 << 24;
+
+/* A render job, as submitted. Settings default to those of `rt render`: the
+ * camera and the screen default to the first camera of the scene */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRequest {
+    pub scene: serde_json::Value,
+    #[serde(default)]
+    pub camera: Option<Camera>,
+    #[serde(default)]
+    pub screen: Option<Screen>,
+    #[serde(default = "default_samples")]
+    pub samples: u64,
+    #[serde(default)]
+    pub min_samples: Option<u64>,
+    #[serde(default = "default_noise_threshold")]
+    pub noise_threshold: f64,
+    #[serde(default)]
+    pub pass_samples: u64,
+    #[serde(default = "default_sampler")]
+    pub sampler: SamplerKind,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default = "default_tile_size")]
+    pub tile_size: u32,
+    #[serde(default = "default_tile_order")]
+    pub tile_order: String,
+    #[serde(default)]
+    pub no_shadows: bool,
+    #[serde(default)]
+    pub no_lambertian: bool,
+    #[serde(default)]
+    pub autofocus: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+/* What `GET /jobs/ID` answers */
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub id: u64,
+    pub state: JobState,
+    pub rays: u64,
+    pub max_rays: u64,
+    pub percent: u64,
+    pub pass: u32,
+    pub tile: usize,
+    pub nb_tiles: usize,
+    pub end: Option<String>,
+    pub error: Option<String>,
+}
+
+/* What is needed to render a job */
+struct Render {
+    scene: Scene,
+    ray_ctx: RayCtx,
+    sampling: SamplingSettings,
+    tiling: TileSettings,
+}
+
+/* The render is dropped, with its scene, once the job is over */
+struct Job {
+    id: u64,
+    render: Option<Arc<Render>>,
+    max_rays: u64,
+    state: JobState,
+    progress: Option<Progress>,
+    error: Option<String>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Job {
+    /* Job of `req`, given its id once queued */
+    fn new(req: JobRequest) -> Result<Job, String> {
+        let scene_json = serde_json::to_vec(&req.scene).map_err(|e| e.to_string())?;
+        let scene = Scene::from_json(&scene_json)?;
+        let scene_camera = scene.camera(None);
+        let camera = req
+            .camera
+            .or_else(|| scene_camera.map(|c| c.camera.clone()))
+            .unwrap_or_else(default_camera);
+        let screen = req
+            .screen
+            .or_else(|| scene_camera.map(|c| c.screen.clone()))
+            .unwrap_or_else(default_screen);
+        if screen.width == 0 || screen.height == 0 {
+            return Err("empty screen".to_owned());
+        }
+        if u64::from(screen.width) * u64::from(screen.height) > MAX_PIXELS {
+            return Err(format!("screen of more than {} pixels", MAX_PIXELS));
+        }
+        let mut ray_ctx = RayCtx::new(&camera, &screen, !req.no_lambertian, !req.no_shadows);
+        if req.autofocus && ray_ctx.autofocus(&scene).is_none() {
+            return Err("nothing to focus on at the center of the image".to_owned());
+        }
+        let sampling = SamplingSettings {
+            min_samples: req.min_samples.unwrap_or(req.samples).min(req.samples),
+            max_samples: req.samples,
+            noise_threshold: req.noise_threshold,
+            pass_samples: req.pass_samples,
+            sampler: req.sampler,
+            seed: req.seed.unwrap_or_else(rand::random),
+        };
+        let tiling = TileSettings {
+            size: req.tile_size,
+            order: TileOrder::parse(&req.tile_order)?,
+            region: Tile {
+                x: 0,
+                y: 0,
+                width: screen.width,
+                height: screen.height,
+            },
+        };
+        Ok(Job {
+            id: 0,
+            max_rays: tiling.region.nb_pixels() * sampling.max_samples,
+            render: Some(Arc::new(Render {
+                scene,
+                ray_ctx,
+                sampling,
+                tiling,
+            })),
+            state: JobState::Queued,
+            progress: None,
+            error: None,
+            stop: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn status(&self) -> JobStatus {
+        let p = self.progress.as_ref();
+        JobStatus {
+            id: self.id,
+            state: self.state,
+            rays: p.map_or(0, |p| p.rays),
+            max_rays: self.max_rays,
+            percent: match self.state {
+                JobState::Done => 100,
+                _ => p.map_or(0, |p| p.percent),
+            },
+            pass: p.map_or(0, |p| p.pass),
+            tile: p.map_or(0, |p| p.nb_tiles_done),
+            nb_tiles: p.map_or(0, |p| p.nb_tiles),
+            end: match self.state {
+                JobState::Running => p.map(|p| p.end.to_rfc3339()),
+                _ => None,
+            },
+            error: self.error.clone(),
+        }
+    }
+}
+
+struct Jobs {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+struct State {
+    dir: PathBuf,
+    jobs: Mutex<Jobs>,
+    /* signaled when a job is queued */
+    queued: Condvar,
+}
+
+fn png_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("job-{}.png", id))
+}
+
+/* Render queued jobs, one after the other */
+fn run_jobs(state: &State) {
+    loop {
+        let (id, render, stop) = {
+            let mut s = state.jobs.lock().unwrap();
+            let idx = loop {
+                if let Some(idx) = s.jobs.iter().position(|j| j.state == JobState::Queued) {
+                    break idx;
+                }
+                s = state.queued.wait(s).unwrap();
+            };
+            let job = &mut s.jobs[idx];
+            job.state = JobState::Running;
+            let render = job.render.clone().expect("queued job without render");
+            (job.id, render, Arc::clone(&job.stop))
+        };
+        let pngpath = png_path(&state.dir, id).to_string_lossy().into_owned();
+        info!("rendering job {} to {}", id, pngpath);
+        let res = render.ray_ctx.render_scene_with(
+            &render.scene,
+            &render.sampling,
+            &render.tiling,
+            &pngpath,
+            &stop,
+            &|p| {
+                let mut s = state.jobs.lock().unwrap();
+                if let Some(job) = s.jobs.iter_mut().find(|j| j.id == id) {
+                    job.progress = Some(p.clone());
+                }
+            },
+        );
+        let mut s = state.jobs.lock().unwrap();
+        if let Some(job) = s.jobs.iter_mut().find(|j| j.id == id) {
+            job.state = match res {
+                Err(why) => {
+                    job.error = Some(why);
+                    JobState::Failed
+                }
+                Ok(()) if stop.load(Ordering::SeqCst) => JobState::Cancelled,
+                Ok(()) => JobState::Done,
+            };
+            job.render = None;
+            info!("job {} {:?}", id, job.state);
+        }
+    }
+}
+
+/* }}} */
+/* {{{ HTTP */
+
+/* Largest request body accepted: scenes with big meshes fit */
+const MAX_BODY: usize = 64 << 20;
+/* Largest request line and headers, all together */
+const MAX_HEAD: usize = 64 << 10;
+/* Time given to a client to send its request or read the response */
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/* Read a request, or the response telling the client what was wrong
+ * with it */
+fn read_request(stream: &TcpStream) -> Result<Request, Response> {
+    let bad = |why: io::Error| Response::error(400, &why.to_string());
+    let mut reader = BufReader::new(stream);
+    let mut head_left = MAX_HEAD;
+    let mut read_line = |line: &mut String| {
+        let n = (&mut reader)
+            .take(head_left as u64)
+            .read_line(line)
+            .map_err(bad)?;
+        head_left -= n;
+        if head_left == 0 && !line.ends_with('\n') {
+            return Err(Response::error(431, "request head too large"));
+        }
+        Ok(n)
+    };
+    let mut line = String::new();
+    read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_owned();
+    let path = parts.next().unwrap_or("").to_owned();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            headers.insert(
+                line[..colon].trim().to_ascii_lowercase(),
+                line[colon + 1..].trim().to_owned(),
+            );
+        }
+    }
+    let len = match headers.get("content-length") {
+        Some(l) => l
+            .parse::<usize>()
+            .map_err(|_| Response::error(400, "invalid content length"))?,
+        None if method == "POST" => return Err(Response::error(400, "missing content length")),
+        None => 0,
+    };
+    if len > MAX_BODY {
+        return Err(Response::error(413, "request body too large"));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).map_err(bad)?;
+    Ok(Request { method, path, body })
+}
+
+struct Response {
+    code: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json<T: serde::Serialize>(code: u16, value: &T) -> Response {
+        Response {
+            code,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).unwrap(),
+        }
+    }
+    fn error(code: u16, msg: &str) -> Response {
+        #[derive(Serialize)]
+        struct Error<'a> {
+            error: &'a str,
+        }
+        Response::json(code, &Error { error: msg })
+    }
+}
+
+fn write_response(mut stream: &TcpStream, r: &Response) -> io::Result<()> {
+    let reason = match r.code {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        r.code,
+        reason,
+        r.content_type,
+        r.body.len()
+    )?;
+    stream.write_all(&r.body)?;
+    stream.flush()
+}
+
+fn route(req: &Request, state: &State) -> Response {
+    let segments: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+    let find = |s: &str| s.parse::<u64>().ok();
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["jobs"]) => {
+            let s = state.jobs.lock().unwrap();
+            let all: Vec<JobStatus> = s.jobs.iter().map(Job::status).collect();
+            Response::json(200, &all)
+        }
+        ("POST", ["jobs"]) => {
+            let req: JobRequest = match serde_json::from_slice(&req.body) {
+                Ok(r) => r,
+                Err(why) => return Response::error(400, &why.to_string()),
+            };
+            /* loading the scene takes a while, let the others poll meanwhile */
+            match Job::new(req) {
+                Ok(mut job) => {
+                    let mut s = state.jobs.lock().unwrap();
+                    let id = s.next_id;
+                    s.next_id += 1;
+                    job.id = id;
+                    /* never resume a render left by a previous server */
+                    let pngpath = png_path(&state.dir, id);
+                    fs::remove_file(Accumulator::path_for(&pngpath.to_string_lossy())).ok();
+                    let status = job.status();
+                    s.jobs.push(job);
+                    state.queued.notify_all();
+                    Response::json(201, &status)
+                }
+                Err(why) => Response::error(400, &why),
+            }
+        }
+        ("GET", ["jobs", id]) => {
+            let s = state.jobs.lock().unwrap();
+            match s.jobs.iter().find(|j| Some(j.id) == find(id)) {
+                Some(job) => Response::json(200, &job.status()),
+                None => Response::error(404, "no such job"),
+            }
+        }
+        ("DELETE", ["jobs", id]) => {
+            let mut s = state.jobs.lock().unwrap();
+            match s.jobs.iter_mut().find(|j| Some(j.id) == find(id)) {
+                Some(job) => {
+                    match job.state {
+                        JobState::Queued => {
+                            job.state = JobState::Cancelled;
+                            job.render = None;
+                        }
+                        JobState::Running => job.stop.store(true, Ordering::SeqCst),
+                        _ => {}
+                    }
+                    Response::json(200, &job.status())
+                }
+                None => Response::error(404, "no such job"),
+            }
+        }
+        ("GET", ["jobs", id, "image"]) => {
+            let s = state.jobs.lock().unwrap();
+            match s.jobs.iter().find(|j| Some(j.id) == find(id)) {
+                Some(job) => match fs::read(png_path(&state.dir, job.id)) {
+                    Ok(png) => Response {
+                        code: 200,
+                        content_type: "image/png",
+                        body: png,
+                    },
+                    Err(_) => Response::error(404, "nothing rendered yet"),
+                },
+                None => Response::error(404, "no such job"),
+            }
+        }
+        (_, ["jobs"]) | (_, ["jobs", _]) | (_, ["jobs", _, "image"]) => {
+            Response::error(405, "method not allowed")
+        }
+        _ => Response::error(404, "not found"),
+    }
+}
+
+/* }}} */
+
+/* Serve the HTTP API on `listener` forever, rendering jobs to `dir` */
+pub fn serve(listener: TcpListener, dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("couldn't create {}: {}", dir.display(), e))?;
+    let state = Arc::new(State {
+        dir: dir.to_path_buf(),
+        jobs: Mutex::new(Jobs {
+            next_id: 1,
+            jobs: Vec::new(),
+        }),
+        queued: Condvar::new(),
+    });
+    {
+        let state = Arc::clone(&state);
+        thread::spawn(move || run_jobs(&state));
+    }
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(why) => {
+                warn!("couldn't accept connection: {}", why);
+                continue;
+            }
+        };
+        stream.set_read_timeout(Some(TIMEOUT)).ok();
+        stream.set_write_timeout(Some(TIMEOUT)).ok();
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            let resp = match read_request(&stream) {
+                Ok(req) => route(&req, &state),
+                Err(resp) => resp,
+            };
+            write_response(&stream, &resp).ok();
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{BaseObject, Sphere};
+    use image::Rgb;
+
+    fn http(addr: &str, method: &str, path: &str, body: &str) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).unwrap();
+        let head_end = resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&resp[..head_end]).into_owned();
+        let code = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (code, resp[head_end + 4..].to_vec())
+    }
+
+    #[test]
+    fn jobs_are_rendered_and_served() {
+        let dir = std::env::temp_dir().join("rt-test-server");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        {
+            let dir = dir.clone();
+            thread::spawn(move || serve(listener, &dir));
+        }
+
+        let mut scene = Scene::new();
+        scene.add(BaseObject::Sphere(Sphere::new(
+            Vec3::origin(),
+            2.,
+            Rgb([200, 10, 10]),
+            true,
+        )));
+        let job = serde_json::json!({
+            "scene": scene,
+            "screen": {"width": 8, "height": 6},
+            "samples": 2,
+            "seed": 1,
+        })
+        .to_string();
+        let (code, body) = http(&addr, "POST", "/jobs", &job);
+        assert_eq!(code, 201, "{}", String::from_utf8_lossy(&body));
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let id = status["id"].as_u64().unwrap();
+
+        let path = format!("/jobs/{}", id);
+        let state = loop {
+            let (code, body) = http(&addr, "GET", &path, "");
+            assert_eq!(code, 200);
+            let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            match status["state"].as_str().unwrap() {
+                "queued" | "running" => thread::sleep(std::time::Duration::from_millis(20)),
+                s => break s.to_owned(),
+            }
+        };
+        assert_eq!(state, "done");
+
+        let (code, png) = http(&addr, "GET", &format!("/jobs/{}/image", id), "");
+        assert_eq!(code, 200);
+        assert_eq!(&png[1..4], b"PNG");
+
+        assert_eq!(http(&addr, "GET", "/jobs/1000", "").0, 404);
+        assert_eq!(http(&addr, "POST", "/jobs", "{").0, 400);
+
+        let raw = |request: &str| {
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).ok();
+            resp.split_whitespace().nth(1).unwrap().to_owned()
+        };
+        assert_eq!(raw("POST /jobs HTTP/1.1\r\n\r\n"), "400");
+        assert_eq!(
+            raw("POST /jobs HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            "400"
+        );
+        assert_eq!(
+            raw("POST /jobs HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n"),
+            "413"
+        );
+        /* exactly as much as is read, so that nothing is left unread */
+        let mut long = "GET /jobs HTTP/1.1\r\nX: ".to_owned();
+        long.push_str(&"x".repeat(MAX_HEAD - long.len()));
+        assert_eq!(raw(&long), "431");
+        let huge = serde_json::json!({
+            "scene": scene,
+            "screen": {"width": 100_000, "height": 100_000},
+        })
+        .to_string();
+        assert_eq!(http(&addr, "POST", "/jobs", &huge).0, 400);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,179 @@
+/* Stereoscopic rendering: the scene is rendered from a left and a right eye,
+ * both looking in the same direction, their images shifted so that they
+ * overlap at the convergence distance, and the two views are combined into
+ * a single image. */
+
+use crate::camera::Camera;
+use crate::raytracer::{print_progress, suffixed_path, RayCtx, SamplingSettings};
+use crate::scene::Scene;
+use crate::tile::TileSettings;
+use image::{Rgba, RgbaImage};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/* How both views are laid out in the image */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    SideBySide,
+    OverUnder,
+    /* left view in the red channel, right view in the green and blue ones */
+    Anaglyph,
+}
+
+impl StereoLayout {
+    pub fn parse(val: &str) -> Result<StereoLayout, String> {
+        match val {
+            "side-by-side" => Ok(StereoLayout::SideBySide),
+            "over-under" => Ok(StereoLayout::OverUnder),
+            "anaglyph" => Ok(StereoLayout::Anaglyph),
+            _ => Err("invalid stereo layout".to_owned()),
+        }
+    }
+}
+
+/* Eyes are `interocular` apart and the centers of their images cross at
+ * `convergence` along the viewing direction: what stands there appears
+ * at the depth of the screen */
+#[derive(Debug, Clone)]
+pub struct Stereo {
+    pub layout: StereoLayout,
+    pub interocular: f64,
+    pub convergence: f64,
+}
+
+impl Stereo {
+    /* Cameras of the left and the right eyes, on both sides of `camera`
+     * and parallel to it. Rather than toeing in, which makes the views
+     * disagree vertically away from their center, each eye shifts its
+     * image towards the other one, which only perspective projections
+     * do */
+    pub fn eyes(&self, camera: &Camera) -> (Camera, Camera) {
+        let (right, _, _) = camera.basis();
+        let eye = |side: f64| {
+            let offset = side * self.interocular / 2.;
+            let mut c = camera.clone();
+            c.position = camera.position.translate(&right, offset);
+            c.look_at = camera.look_at.translate(&right, offset);
+            c.shift.0 -= offset / self.convergence;
+            c
+        };
+        (eye(-1.), eye(1.))
+    }
+
+    /* Image of a single eye, next to the combined image at `pngpath` */
+    pub fn path_for(pngpath: &str, eye: &str) -> String {
+        suffixed_path(pngpath, eye)
+    }
+
+    /* Render both eyes next to `pngpath`, each with its own checkpoint, then
+     * combine them into `pngpath`, unless `stop` gets set */
+    pub fn render(
+        &self,
+        ray_ctx: &RayCtx,
+        scene: &Scene,
+        sampling: &SamplingSettings,
+        tiling: &TileSettings,
+        pngpath: &str,
+        stop: &AtomicBool,
+    ) -> Result<(), String> {
+        let (left, right) = self.eyes(&ray_ctx.camera);
+        let mut views = Vec::new();
+        for (eye, camera) in &[("left", left), ("right", right)] {
+            let path = Stereo::path_for(pngpath, eye);
+            let eye_ctx = RayCtx::new(
+                camera,
+                &ray_ctx.screen,
+                ray_ctx.with_lambertian,
+                ray_ctx.with_shadows,
+            );
+            eye_ctx
+                .render_scene_with(scene, sampling, tiling, &path, stop, &print_progress)
+                .map_err(|why| format!("{} eye: {}", eye, why))?;
+            println!();
+            if stop.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let view =
+                image::open(&path).map_err(|why| format!("couldn't open {}: {}", path, why))?;
+            views.push(view.to_rgba());
+        }
+        self.combine(&views[0], &views[1])
+            .save(pngpath)
+            .map_err(|why| format!("couldn't save {}: {}", pngpath, why))
+    }
+
+    pub fn combine(&self, left: &RgbaImage, right: &RgbaImage) -> RgbaImage {
+        let (w, h) = left.dimensions();
+        match self.layout {
+            StereoLayout::SideBySide => {
+                let mut buf = RgbaImage::new(2 * w, h);
+                for (x, y, p) in left.enumerate_pixels() {
+                    buf.put_pixel(x, y, *p);
+                    buf.put_pixel(w + x, y, *right.get_pixel(x, y));
+                }
+                buf
+            }
+            StereoLayout::OverUnder => {
+                let mut buf = RgbaImage::new(w, 2 * h);
+                for (x, y, p) in left.enumerate_pixels() {
+                    buf.put_pixel(x, y, *p);
+                    buf.put_pixel(x, h + y, *right.get_pixel(x, y));
+                }
+                buf
+            }
+            StereoLayout::Anaglyph => {
+                let mut buf = RgbaImage::new(w, h);
+                for (x, y, l) in left.enumerate_pixels() {
+                    let r = right.get_pixel(x, y);
+                    buf.put_pixel(x, y, Rgba([l[0], r[1], r[2], l[3].min(r[3])]));
+                }
+                buf
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Projection;
+    use crate::maths::Vec3;
+    use crate::raytracer::Screen;
+
+    #[test]
+    fn eyes_converge_at_the_convergence_distance() {
+        let camera = Camera::new(Vec3::new(0., 1., 0.), &Vec3::new(1., 0., 1.));
+        let stereo = Stereo {
+            layout: StereoLayout::Anaglyph,
+            interocular: 0.5,
+            convergence: 10.,
+        };
+        let (left, right) = stereo.eyes(&camera);
+        assert!((left.position.length_sq_to(&right.position) - 0.25).abs() < 1e-9);
+        /* the eyes look in parallel, their image centers meeting at the
+         * convergence distance */
+        let target = camera.position.translate(&camera.direction(), 10.);
+        let screen = Screen {
+            width: 4,
+            height: 2,
+        };
+        for eye in &[&left, &right] {
+            assert!(eye.direction().to(&camera.direction()).length_sq() < 1e-18);
+            for projection in &[Projection::Legacy, Projection::Rectilinear] {
+                let mut eye = (*eye).clone();
+                eye.projection = *projection;
+                let (origin, direction) =
+                    RayCtx::new(&eye, &screen, true, true).primary_ray(0.5, 0.5);
+                let to_target = origin.to(&target).normalize();
+                assert!(
+                    direction.to(&to_target).length_sq() < 1e-18,
+                    "{:?}",
+                    projection
+                );
+            }
+        }
+        /* the left eye is on the left when looking forward */
+        let (r, _, _) = camera.basis();
+        assert!(camera.position.to(&left.position).dot_product(&r) < 0.);
+        assert_eq!(Stereo::path_for("out/a.png", "left"), "out/a.left.png");
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+
+/* Split the image in tiles and order them so that the interesting part of
+ * the image (its center) is rendered first */
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+impl Tile {
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+    pub fn intersects(&self, other: &Tile) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+    /* Whether the tile is not empty and lies within a `width`x`height`
+     * image */
+    pub fn fits_in(&self, width: u32, height: u32) -> bool {
+        let end = |start: u32, len: u32, max: u32| match start.checked_add(len) {
+            Some(end) => len > 0 && end <= max,
+            None => false,
+        };
+        end(self.x, self.width, width) && end(self.y, self.height, height)
+    }
+    pub fn nb_pixels(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+}
+impl fmt::Display for Tile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{}x{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn parse(val: &str) -> Result<TileOrder, String> {
+        match val {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err("invalid tile order".to_owned()),
+        }
+    }
+}
+
+/* How the `region` of the image to render is split in tiles */
+#[derive(Debug, Clone)]
+pub struct TileSettings {
+    pub size: u32,
+    pub order: TileOrder,
+    pub region: Tile,
+}
+
+/* Position of `d` along a Hilbert curve covering a n×n grid, n being a
+ * power of 2 */
+fn hilbert_d2xy(n: u32, d: u64) -> (u32, u32) {
+    let mut x = 0_u32;
+    let mut y = 0_u32;
+    let mut t = d;
+    let mut s = 1_u32;
+    while s < n {
+        let rx = (1 & (t / 2)) as u32;
+        let ry = (1 & (t ^ u64::from(rx))) as u32;
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+/* Cells of a cols×rows grid, walking a square spiral from its center */
+fn spiral(cols: u32, rows: u32) -> Vec<(u32, u32)> {
+    let total = (cols as usize) * (rows as usize);
+    let mut cells = Vec::with_capacity(total);
+    let mut x = (cols as i64 - 1) / 2;
+    let mut y = (rows as i64 - 1) / 2;
+    let (mut dx, mut dy) = (1_i64, 0_i64);
+    let mut leg = 1;
+    while cells.len() < total {
+        for _ in 0..2 {
+            for _ in 0..leg {
+                if x >= 0 && y >= 0 && x < cols as i64 && y < rows as i64 {
+                    cells.push((x as u32, y as u32));
+                }
+                x += dx;
+                y += dy;
+            }
+            /* turn right */
+            let t = dx;
+            dx = -dy;
+            dy = t;
+        }
+        leg += 1;
+    }
+    cells
+}
+
+/* Band `index` of `count` horizontal bands of a width×height image, the
+ * bands being made of whole rows of tiles */
+pub fn partition(
+    width: u32,
+    height: u32,
+    size: u32,
+    index: u32,
+    count: u32,
+) -> Result<Tile, String> {
+    let rows = height.div_ceil(size.max(1));
+    if count == 0 || index >= count {
+        return Err("invalid tile partition".to_owned());
+    }
+    if count > rows {
+        return Err(format!("only {} rows of tiles to share", rows));
+    }
+    let first = u64::from(index) * u64::from(rows) / u64::from(count);
+    let last = u64::from(index + 1) * u64::from(rows) / u64::from(count);
+    let y = first as u32 * size;
+    let y_end = (last as u32 * size).min(height);
+    Ok(Tile {
+        x: 0,
+        y,
+        width,
+        height: y_end - y,
+    })
+}
+
+pub fn generate_tiles(settings: &TileSettings) -> Vec<Tile> {
+    let region = &settings.region;
+    let width = region.width;
+    let height = region.height;
+    let size = settings.size.max(1);
+    let cols = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+    let cells: Vec<(u32, u32)> = match settings.order {
+        TileOrder::Scanline => (0..rows)
+            .flat_map(|r| (0..cols).map(move |c| (c, r)))
+            .collect(),
+        TileOrder::Spiral => spiral(cols, rows),
+        TileOrder::Hilbert => {
+            let n = cols.max(rows).next_power_of_two();
+            (0..u64::from(n) * u64::from(n))
+                .map(|d| hilbert_d2xy(n, d))
+                .filter(|(c, r)| *c < cols && *r < rows)
+                .collect()
+        }
+    };
+    cells
+        .into_iter()
+        .map(|(c, r)| {
+            let x = c * size;
+            let y = r * size;
+            Tile {
+                x: region.x + x,
+                y: region.y + y,
+                width: size.min(width - x),
+                height: size.min(height - y),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_the_image_once() {
+        for order in &[TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            let settings = TileSettings {
+                size: 16,
+                order: *order,
+                region: Tile {
+                    x: 0,
+                    y: 0,
+                    width: 100,
+                    height: 37,
+                },
+            };
+            let tiles = generate_tiles(&settings);
+            let mut seen = vec![0_u32; 100 * 37];
+            for t in &tiles {
+                for y in t.y..t.y + t.height {
+                    for x in t.x..t.x + t.width {
+                        seen[(y * 100 + x) as usize] += 1;
+                    }
+                }
+            }
+            assert!(seen.iter().all(|n| *n == 1), "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn partition_covers_the_image() {
+        let mut y = 0;
+        for i in 0..3 {
+            let band = partition(100, 70, 16, i, 3).unwrap();
+            assert_eq!(band.y, y);
+            assert_eq!(band.y % 16, 0);
+            y += band.height;
+        }
+        assert_eq!(y, 70);
+        assert!(partition(100, 70, 16, 0, 6).is_err());
+    }
+
+    #[test]
+    fn regions_fit_without_overflowing() {
+        let tile = |x, y, width, height| Tile {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert!(tile(10, 20, 90, 50).fits_in(100, 70));
+        assert!(!tile(10, 20, 91, 50).fits_in(100, 70));
+        assert!(!tile(10, 20, 0, 50).fits_in(100, 70));
+        assert!(!tile(u32::MAX, 0, 2, 1).fits_in(u32::MAX, u32::MAX));
+    }
+}
@@ -0,0 +1,103 @@
+/* Turntable: views of the scene from cameras orbiting around its center, to
+ * be rendered as frames or gathered into a contact sheet. */
+
+use crate::camera::Camera;
+use crate::maths::{Aabb, Vec3};
+use image::{GenericImage, RgbaImage};
+use std::f64::consts::PI;
+
+/* `views` cameras evenly spread on a circle around the center of the
+ * bounds, looking down at it from `elevation` degrees */
+#[derive(Debug, Clone)]
+pub struct Turntable {
+    pub views: u32,
+    pub elevation: f64,
+    /* from the center, 0 to fit the bounds in the field of view */
+    pub distance: f64,
+}
+
+impl Turntable {
+    /* `camera` moved around `bounds`, starting from where it looks along */
+    pub fn cameras(&self, camera: &Camera, bounds: &Aabb, aspect_ratio: f64) -> Vec<Camera> {
+        let up = camera.up.normalize();
+        let center = bounds.center();
+
+        /* horizontal basis, the first view facing the same way as `camera` */
+        let (right, _, forward) = camera.basis();
+        let mut front = forward.translate(&up, -forward.dot_product(&up));
+        if front.length_sq() < 1e-12 {
+            front = up.cross_product(&right);
+        }
+        let front = front.normalize();
+        let side = up.cross_product(&front).normalize();
+
+        let (sin_e, cos_e) = self.elevation.to_radians().sin_cos();
+        (0..self.views)
+            .map(|k| {
+                let (sin_a, cos_a) = (2. * PI * f64::from(k) / f64::from(self.views)).sin_cos();
+                let d = Vec3::new(
+                    cos_e * (cos_a * front.x + sin_a * side.x) - sin_e * up.x,
+                    cos_e * (cos_a * front.y + sin_a * side.y) - sin_e * up.y,
+                    cos_e * (cos_a * front.z + sin_a * side.z) - sin_e * up.z,
+                );
+                let mut c = camera.clone();
+                c.look_at = center.clone();
+                if self.distance > 0. {
+                    c.position = center.translate(&d, -self.distance);
+                    c.focus_distance = self.distance;
+                } else {
+                    c.position = center.translate(&d, -1.);
+                    c.frame(bounds, aspect_ratio);
+                }
+                c
+            })
+            .collect()
+    }
+}
+
+/* Images laid out in a grid of `columns`, left to right then top to
+ * bottom */
+pub fn contact_sheet(images: &[RgbaImage], columns: u32) -> RgbaImage {
+    let (w, h) = images[0].dimensions();
+    let nb = images.len() as u32;
+    let rows = nb.div_ceil(columns);
+    let mut sheet = RgbaImage::new(w * columns, h * rows);
+    for (k, img) in images.iter().enumerate() {
+        let k = k as u32;
+        sheet.copy_from(img, (k % columns) * w, (k / columns) * h);
+    }
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn views_orbit_around_the_center() {
+        let bounds = Aabb {
+            min: Vec3::new(-1., 0., 4.),
+            max: Vec3::new(1., 2., 6.),
+        };
+        let camera = Camera::new(Vec3::new(0., 1., 0.), &Vec3::new(0., 0., 1.));
+        let turntable = Turntable {
+            views: 4,
+            elevation: 30.,
+            distance: 10.,
+        };
+        let cameras = turntable.cameras(&camera, &bounds, 16. / 9.);
+        assert_eq!(cameras.len(), 4);
+        let center = bounds.center();
+        for c in &cameras {
+            assert!((c.position.length_sq_to(&center) - 100.).abs() < 1e-9);
+            assert!((c.position.y - center.y - 5.).abs() < 1e-9);
+            assert_eq!(c.look_at, center);
+        }
+        /* the first view looks the same way as the camera */
+        assert!(cameras[0].position.z < center.z);
+        assert!((cameras[0].position.x - center.x).abs() < 1e-9);
+        /* opposite views face each other */
+        let mid = cameras[0].position.avg(&cameras[2].position);
+        assert!((mid.x - center.x).abs() < 1e-9 && (mid.z - center.z).abs() < 1e-9);
+    }
+}